@@ -2,8 +2,13 @@
 
 #define PACKET_SIZE 5
 
-#define BTN_COUNT 4
-const uint8_t btns[BTN_COUNT] = {PB6, PB5, PB4, PB3};
+// 0-3: spare, spare, spare, panic
+// 4-10: step left, step right, toggle held notes, clear step, copy, paste,
+// clear channel
+// 11-13: play/stop, record, record mode
+#define BTN_COUNT 14
+const uint8_t btns[BTN_COUNT] = {PB6, PB5, PB4, PB3, PB7, PB8, PB9, PB12, PB13, PB14, PB15,
+                                 PA4, PA5, PA6};
 int8_t btn_states[BTN_COUNT];
 
 #define POT_COUNT 4
@@ -50,8 +55,9 @@ void send_packet(uint8_t cmd, uint8_t data1, uint8_t data2, uint8_t data3)
 
   uint8_t checksum = cmd + data1 + data2 + data3;
 
-  char data[5] = {cmd, data1, data2, data3, checksum};
-  Serial1.write(data);
+  // the data bytes can be 0, so write by length rather than as a string
+  uint8_t data[PACKET_SIZE] = {cmd, data1, data2, data3, checksum};
+  Serial1.write(data, PACKET_SIZE);
 }
 
 void uint16_to_uint8(uint16_t value, uint8_t out[])
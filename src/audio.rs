@@ -0,0 +1,366 @@
+//! Audio output: device selection, stream format negotiation and the
+//! callback driving the worker.
+
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use log::{error, info, warn};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{BufferSize, Device, Sample, SampleFormat, SampleRate, StreamConfig, SupportedBufferSize};
+use synthesizer_io_core::module::N_SAMPLES_PER_CHUNK;
+use synthesizer_io_core::worker::Worker;
+
+use crate::clock::Clock;
+use crate::config;
+use crate::diagnostics::Diagnostics;
+
+/// What the application asks of the audio output. The negotiated format
+/// may differ, see `AudioOutput`.
+#[derive(Clone, Debug)]
+pub struct AudioOptions {
+    /// Output device name, or a unique part of it. The host default is used
+    /// if unset.
+    pub device: Option<String>,
+    pub sample_rate: u32,
+    /// Buffer size in frames, clamped to what the device supports.
+    pub buffer_size: Option<u32>,
+}
+
+impl Default for AudioOptions {
+    fn default() -> AudioOptions {
+        AudioOptions {
+            device: None,
+            sample_rate: config::SAMPLE_HZ as u32,
+            buffer_size: None,
+        }
+    }
+}
+
+/// How long to wait between attempts to reopen a lost device.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+/// How often a running stream checks whether it should stop.
+const POLL_STOP: Duration = Duration::from_millis(100);
+
+/// An output device with a negotiated stream format, ready to be started.
+pub struct AudioOutput {
+    device: Device,
+    config: StreamConfig,
+    sample_format: SampleFormat,
+    // kept to reopen the device if it goes away
+    options: AudioOptions,
+}
+
+/// Names of the output devices of the default host.
+pub fn list_devices() -> Result<Vec<String>, Box<dyn Error>> {
+    let host = cpal::default_host();
+    let mut names = vec![];
+    for device in host.output_devices()? {
+        names.push(device.name()?);
+    }
+    Ok(names)
+}
+
+fn find_device(name: &str) -> Result<Device, Box<dyn Error>> {
+    let host = cpal::default_host();
+    let mut partial = vec![];
+    for device in host.output_devices()? {
+        let device_name = device.name()?;
+        if device_name == name {
+            return Ok(device);
+        }
+        if device_name.to_lowercase().contains(&name.to_lowercase()) {
+            partial.push(device);
+        }
+    }
+    match partial.len() {
+        0 => Err(format!("no output device matching \"{}\"", name).into()),
+        1 => Ok(partial.remove(0)),
+        _ => Err(format!("more than one output device matches \"{}\"", name).into()),
+    }
+}
+
+impl AudioOutput {
+    /// Select a device and find a stream format for it.
+    ///
+    /// Configurations supporting the requested sample rate are preferred,
+    /// then stereo ones, then f32 over i16 over u16 samples. If no
+    /// configuration supports the rate, the device default is used.
+    pub fn open(options: &AudioOptions) -> Result<AudioOutput, Box<dyn Error>> {
+        let device = match options.device {
+            Some(ref name) => find_device(name)?,
+            None => cpal::default_host()
+                .default_output_device()
+                .ok_or("no output device available")?,
+        };
+
+        let rate = SampleRate(options.sample_rate);
+        let format_rank = |format: SampleFormat| match format {
+            SampleFormat::F32 => 0,
+            SampleFormat::I16 => 1,
+            SampleFormat::U16 => 2,
+        };
+        let best = device
+            .supported_output_configs()?
+            .filter(|c| c.min_sample_rate() <= rate && rate <= c.max_sample_rate())
+            .min_by_key(|c| ((c.channels() != 2) as u8, format_rank(c.sample_format())));
+        let supported = match best {
+            Some(range) => range.with_sample_rate(rate),
+            None => {
+                let default = device.default_output_config()?;
+                warn!(
+                    "{} Hz not supported by device, using {} Hz",
+                    options.sample_rate,
+                    default.sample_rate().0
+                );
+                default
+            }
+        };
+
+        let mut config: StreamConfig = supported.config();
+        if let Some(frames) = options.buffer_size {
+            config.buffer_size = match *supported.buffer_size() {
+                SupportedBufferSize::Range { min, max } => BufferSize::Fixed(frames.max(min).min(max)),
+                SupportedBufferSize::Unknown => BufferSize::Fixed(frames),
+            };
+        }
+
+        Ok(AudioOutput {
+            device,
+            config,
+            sample_format: supported.sample_format(),
+            options: options.clone(),
+        })
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.config.sample_rate.0 as f32
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{}: {:?} {:?}",
+            self.device.name().unwrap_or_default(),
+            self.sample_format,
+            self.config
+        )
+    }
+
+    /// Start the stream, pulling audio from the worker and keeping the clock
+    /// anchored to it. Returns once `stop` is set.
+    ///
+    /// If the device goes away, or the stream fails to start, the device is
+    /// reopened with the same options and the stream restarted. The worker
+    /// and the timeline carry on where they were.
+    pub fn run(self, worker: Worker, clock: Arc<Clock>, diagnostics: Arc<Diagnostics>, stop: &AtomicBool) {
+        let renderer = Arc::new(Mutex::new(Renderer::new(worker, clock)));
+        let mut output = self;
+        loop {
+            let (fail_tx, fail_rx) = mpsc::channel();
+            match output.start(renderer.clone(), diagnostics.clone(), fail_tx) {
+                Ok(stream) => loop {
+                    match fail_rx.recv_timeout(POLL_STOP) {
+                        Ok(reason) => {
+                            drop(stream);
+                            error!("audio stream stopped: {}, restarting", reason);
+                            break;
+                        }
+                        Err(_) if stop.load(Ordering::SeqCst) => return,
+                        Err(_) => {}
+                    }
+                },
+                Err(e) => error!("can't start audio stream: {}", e),
+            }
+            output = match AudioOutput::reopen(&output.options, output.config.sample_rate, stop) {
+                Some(output) => output,
+                None => return,
+            };
+            info!("Format: {}", output.describe());
+        }
+    }
+
+    /// Open the device again, waiting until it's back with the sample rate
+    /// the engine was set up for, or until `stop` is set.
+    fn reopen(options: &AudioOptions, rate: SampleRate, stop: &AtomicBool) -> Option<AudioOutput> {
+        let options = AudioOptions {
+            sample_rate: rate.0,
+            ..options.clone()
+        };
+        while !stop.load(Ordering::SeqCst) {
+            std::thread::sleep(RESTART_DELAY);
+            match AudioOutput::open(&options) {
+                Ok(output) if output.config.sample_rate == rate => return Some(output),
+                Ok(output) => warn!(
+                    "audio device reopened at {} Hz instead of {} Hz, retrying",
+                    output.config.sample_rate.0, rate.0
+                ),
+                Err(e) => warn!("waiting for audio device: {}", e),
+            }
+        }
+        None
+    }
+
+    fn start(
+        &self,
+        renderer: Arc<Mutex<Renderer>>,
+        diagnostics: Arc<Diagnostics>,
+        fail: mpsc::Sender<String>,
+    ) -> Result<cpal::Stream, Box<dyn Error>> {
+        let stream = match self.sample_format {
+            SampleFormat::F32 => self.build_stream::<f32>(renderer, diagnostics, fail)?,
+            SampleFormat::I16 => self.build_stream::<i16>(renderer, diagnostics, fail)?,
+            SampleFormat::U16 => self.build_stream::<u16>(renderer, diagnostics, fail)?,
+        };
+        stream.play()?;
+        Ok(stream)
+    }
+
+    fn build_stream<T: Sample>(
+        &self,
+        renderer: Arc<Mutex<Renderer>>,
+        diagnostics: Arc<Diagnostics>,
+        fail: mpsc::Sender<String>,
+    ) -> Result<cpal::Stream, cpal::BuildStreamError> {
+        let channels = self.config.channels as usize;
+        let sample_rate = self.config.sample_rate.0 as f64;
+        let diagnostics_cb = diagnostics.clone();
+        let mut last_callback: Option<u64> = None;
+        self.device.build_output_stream(
+            &self.config,
+            move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
+                let start = Clock::host_now();
+                let buffer_frames = (data.len() / channels) as u64;
+                let period_ns = (buffer_frames as f64 * 1e9 / sample_rate) as u64;
+                // a callback arriving more than a whole buffer late means
+                // the device ran dry in between
+                if let Some(last) = last_callback {
+                    if start.saturating_sub(last) > 2 * period_ns {
+                        diagnostics_cb.record_xrun();
+                    }
+                }
+                last_callback = Some(start);
+
+                // only contended while the stream is being restarted
+                let mut renderer = match renderer.try_lock() {
+                    Ok(renderer) => renderer,
+                    Err(_) => {
+                        for sample in data.iter_mut() {
+                            *sample = T::from(&0.0f32);
+                        }
+                        return;
+                    }
+                };
+                let timestamp = info.timestamp();
+                let output_latency = timestamp
+                    .playback
+                    .duration_since(&timestamp.callback)
+                    .map_or(0, |latency| latency.as_nanos() as u64);
+                renderer.start_callback(buffer_frames, output_latency);
+                for frame in data.chunks_mut(channels) {
+                    let (left, right) = renderer.next_frame();
+                    write_frame(frame, left, right);
+                }
+
+                if period_ns > 0 {
+                    let elapsed = Clock::host_now().saturating_sub(start);
+                    diagnostics_cb.record_callback(elapsed as f64 / period_ns as f64);
+                }
+            },
+            move |err| match err {
+                cpal::StreamError::DeviceNotAvailable => {
+                    let _ = fail.send(err.to_string());
+                }
+                cpal::StreamError::BackendSpecific { .. } => {
+                    // mostly underruns reported by the backend
+                    warn!("audio stream error: {}", err);
+                    diagnostics.record_xrun();
+                }
+            },
+        )
+    }
+}
+
+/// Drive the worker in real time without an audio device, discarding its
+/// output. Returns once `stop` is set.
+pub fn run_headless(worker: Worker, clock: Arc<Clock>, buffer_frames: u32, stop: &AtomicBool) {
+    let buffer_frames = buffer_frames.max(1) as u64;
+    let mut renderer = Renderer::new(worker, clock.clone());
+    let period = clock.frames_to_ns(buffer_frames);
+    let mut deadline = Clock::host_now();
+    while !stop.load(Ordering::SeqCst) {
+        renderer.start_callback(buffer_frames, 0);
+        for _ in 0..buffer_frames {
+            renderer.next_frame();
+        }
+        deadline += period;
+        let now = Clock::host_now();
+        if deadline > now {
+            std::thread::sleep(std::time::Duration::from_nanos(deadline - now));
+        }
+    }
+}
+
+/// Write a stereo frame to a device frame with any number of channels.
+/// Mono devices get the average, extra channels stay silent.
+fn write_frame<T: Sample>(frame: &mut [T], left: f32, right: f32) {
+    match frame.len() {
+        1 => frame[0] = T::from(&((left + right) * 0.5)),
+        _ => {
+            frame[0] = T::from(&left);
+            frame[1] = T::from(&right);
+            for sample in frame[2..].iter_mut() {
+                *sample = T::from(&0.0f32);
+            }
+        }
+    }
+}
+
+/// Hands out the worker's output one frame at a time, so device buffers
+/// don't need to be a multiple of the chunk size.
+struct Renderer {
+    worker: Worker,
+    clock: Arc<Clock>,
+    left: [f32; N_SAMPLES_PER_CHUNK],
+    right: [f32; N_SAMPLES_PER_CHUNK],
+    pos: usize,
+    // frames handed to the device so far
+    frames: u64,
+}
+
+impl Renderer {
+    fn new(worker: Worker, clock: Arc<Clock>) -> Renderer {
+        Renderer {
+            worker,
+            clock,
+            left: [0.0; N_SAMPLES_PER_CHUNK],
+            right: [0.0; N_SAMPLES_PER_CHUNK],
+            pos: N_SAMPLES_PER_CHUNK,
+            frames: 0,
+        }
+    }
+
+    fn start_callback(&mut self, buffer_frames: u64, output_latency_ns: u64) {
+        let timeline = self.clock.frames_to_ns(self.frames);
+        self.clock.update(timeline, buffer_frames, output_latency_ns);
+    }
+
+    fn next_frame(&mut self) -> (f32, f32) {
+        if self.pos == N_SAMPLES_PER_CHUNK {
+            self.render_chunk();
+        }
+        let frame = (self.left[self.pos], self.right[self.pos]);
+        self.pos += 1;
+        self.frames += 1;
+        frame
+    }
+
+    fn render_chunk(&mut self) {
+        let timestamp = self.clock.frames_to_ns(self.frames);
+        let bufs = self.worker.work(timestamp);
+        self.left.copy_from_slice(bufs[0].get());
+        self.right.copy_from_slice(bufs[bufs.len().min(2) - 1].get());
+        self.pos = 0;
+    }
+}
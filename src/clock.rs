@@ -0,0 +1,93 @@
+//! Mapping between the host clock and the audio timeline.
+//!
+//! The worker is driven with timestamps counted from the samples actually
+//! rendered, in nanoseconds at the stream sample rate. Threads producing
+//! events (MIDI, sequencer) only know the host time, so the audio callback
+//! publishes which timeline position it is rendering at what host time, and
+//! producers convert through that anchor.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct Clock {
+    sample_rate: f64,
+    // sequence counter guarding the anchor, odd while it is being written
+    seq: AtomicU64,
+    anchor_host: AtomicU64,
+    anchor_timeline: AtomicU64,
+    // duration of the last device buffer, events are delayed by this much so
+    // they land in the next buffer at a constant offset
+    buffer_ns: AtomicU64,
+    // predicted delay between the callback and the samples being heard
+    output_latency_ns: AtomicU64,
+}
+
+impl Clock {
+    pub fn new(sample_rate: f32) -> Clock {
+        Clock {
+            sample_rate: sample_rate as f64,
+            seq: AtomicU64::new(0),
+            anchor_host: AtomicU64::new(0),
+            anchor_timeline: AtomicU64::new(0),
+            buffer_ns: AtomicU64::new(0),
+            output_latency_ns: AtomicU64::new(0),
+        }
+    }
+
+    /// The timeline position of a frame count, in nanoseconds.
+    pub fn frames_to_ns(&self, frames: u64) -> u64 {
+        (frames as f64 * 1e9 / self.sample_rate) as u64
+    }
+
+    /// Current host time in nanoseconds, on the same clock used for anchors.
+    pub fn host_now() -> u64 {
+        time::precise_time_ns()
+    }
+
+    /// Called from the audio callback with the timeline position of the
+    /// first frame it writes.
+    pub fn update(&self, timeline_ns: u64, buffer_frames: u64, output_latency_ns: u64) {
+        let host = Clock::host_now();
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq + 1, Ordering::Release);
+        self.anchor_host.store(host, Ordering::Release);
+        self.anchor_timeline.store(timeline_ns, Ordering::Release);
+        self.buffer_ns.store(self.frames_to_ns(buffer_frames), Ordering::Release);
+        self.output_latency_ns.store(output_latency_ns, Ordering::Release);
+        self.seq.store(seq + 2, Ordering::Release);
+    }
+
+    /// Timestamp for an event happening now.
+    pub fn now(&self) -> u64 {
+        self.at(Clock::host_now())
+    }
+
+    /// Timestamp for an event at the given host time.
+    ///
+    /// Before the stream has started this is 0, so events apply as soon as
+    /// the worker sees them.
+    pub fn at(&self, host_ns: u64) -> u64 {
+        loop {
+            let seq = self.seq.load(Ordering::Acquire);
+            if seq == 0 {
+                return 0;
+            }
+            if seq % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let anchor_host = self.anchor_host.load(Ordering::Acquire);
+            let anchor_timeline = self.anchor_timeline.load(Ordering::Acquire);
+            let buffer_ns = self.buffer_ns.load(Ordering::Acquire);
+            if self.seq.load(Ordering::Acquire) != seq {
+                continue;
+            }
+            let since = host_ns as i64 - anchor_host as i64;
+            return (anchor_timeline as i64 + buffer_ns as i64 + since).max(0) as u64;
+        }
+    }
+
+    /// Delay between an event timestamped with `now` and it being heard.
+    pub fn latency_ns(&self) -> u64 {
+        self.buffer_ns.load(Ordering::Relaxed) + self.output_latency_ns.load(Ordering::Relaxed)
+    }
+}
@@ -1,4 +1,446 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::note::{Allocation, Priority, Unison};
+use crate::sequencer::{Groove, Swing};
+
 pub const VOICE_COUNT: usize = 16;
 pub const CHANNEL_COUNT: usize = 3;
 pub const SAMPLE_HZ: f32 = 48000.0;
 pub const MAX_STEPS: usize = 24;
+
+/// Config file read when `--config` isn't given, if it exists.
+pub const DEFAULT_CONFIG_FILE: &str = "synthseq.toml";
+
+const USAGE: &str = "usage: synthseq [options]
+
+  --config FILE          read settings from a TOML file (default synthseq.toml)
+  --channels N           number of channels, channel 0 takes live input
+  --voices N             voices per channel
+  --steps N              maximum sequence length
+  --sample-rate HZ       requested audio sample rate
+  --buffer-size FRAMES   requested audio buffer size
+  --audio-device NAME    output device, or a unique part of its name
+  --list-audio-devices   print the output devices and exit
+  --midi-port NAME       MIDI input port, or a unique part of its name
+  --list-midi-ports      print the MIDI input ports and exit
+  --serial-port NAME     serial port of the control panel
+  --serial-baud N        baud rate of the control panel
+  --project FILE         project to load at startup
+  --autosave FILE        save the project here on exit (default not
+                         saved)
+  --synth poly|mono      voice architecture
+  --channel N            channel selected at startup
+  --allocation MODE      voice allocation: round-robin, oldest, quietest,
+                         retrigger, lowest-note or highest-note
+  --priority last|low|high
+                         which held note a mono synth plays
+  --legato               mono synth doesn't retrigger overlapping notes
+  --glide SECONDS        portamento time
+  --unison N             voices stacked on every note
+  --detune SEMITONES     pitch spread of unison voices (default 0.1)
+  --spread AMOUNT        stereo spread of unison voices, 0..1 (default 0.5)
+  --bend-range SEMITONES pitch bend range (default 2)
+  --mpe                  MPE on the live channel, MIDI channel 1 is the
+                         master channel, 2-16 play one voice each
+  --mpe-bend-range SEMITONES
+                         per-note pitch bend range in MPE (default 48)
+  --mod-wheel-vibrato    the mod wheel (CC1) plays vibrato instead of
+                         setting the cutoff, which stays on CC74
+  --a4 HZ                reference frequency of A4 (default 440)
+  --tuning SCALE         add a tuning table, a Scala .scl file or N for N
+                         equal steps per octave; every channel plays the
+                         first one unless --channel-tunings says otherwise
+  --keymap FILE          Scala .kbm keyboard mapping for the last --tuning
+  --channel-tunings LIST tuning table of each channel, comma separated, 0
+                         is standard tuning and 1 the first --tuning
+  --stuck-note-timeout S release live notes held longer than S seconds,
+                         0 to never release them (default 0)
+  --count-in BARS        bars of clicks before recording starts (default 1)
+  --swing AMOUNT         how much of the groove the sequencers play, 0
+                         (straight) to 1
+  --groove swing|shuffle|push
+                         timing template of the sequencers
+  --seed N               seed of the step probabilities, renders use 0
+                         unless given
+  --headless             run without an audio device
+  --render FILE          render the project to a WAV file and exit
+  --bars N               length of the render in bars
+  --log-level FILTER     error, warn, info, debug or trace, optionally per
+                         module, e.g. info,midi=debug,sequencer=trace
+  --diagnostics          periodically log timing and event statistics
+  --help                 print this message";
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SynthMode {
+    Poly,
+    Mono,
+}
+
+/// Application settings, from the config file and the command line.
+/// Command line flags take precedence.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "channels")]
+    pub channel_count: usize,
+    #[serde(rename = "voices")]
+    pub voice_count: usize,
+    #[serde(rename = "steps")]
+    pub max_steps: usize,
+    #[serde(rename = "sample_rate")]
+    pub sample_hz: f32,
+    pub buffer_size: Option<u32>,
+    pub audio_device: Option<String>,
+
+    /// MIDI input port. If unset, the only port is used, or the user is
+    /// asked to pick one.
+    pub midi_port: Option<String>,
+    /// Serial port of the control panel. The panel is disabled if unset.
+    pub serial_port: Option<String>,
+    pub serial_baud: u32,
+
+    pub project: Option<PathBuf>,
+    /// Where the project is saved on exit. Nothing is saved without it, so
+    /// the loaded project is never overwritten unless asked.
+    pub autosave: Option<PathBuf>,
+    pub synth: SynthMode,
+    pub channel: usize,
+    /// Voice allocation of every channel at startup.
+    pub allocation: Allocation,
+    /// Note priority and legato of the mono synth.
+    pub priority: Priority,
+    pub legato: bool,
+    /// Portamento time in seconds.
+    pub glide: f32,
+    /// Unison voice count, detune in semitones and stereo spread (0..1).
+    pub unison: usize,
+    pub detune: f32,
+    pub spread: f32,
+    /// Pitch bend range in semitones, and the per-note one of MPE.
+    pub bend_range: f32,
+    pub mpe: bool,
+    pub mpe_bend_range: f32,
+    /// The mod wheel plays vibrato. Otherwise it sets the cutoff, as CC74
+    /// does.
+    pub mod_wheel_vibrato: bool,
+    /// Reference frequency of A4, for every tuning without a keymap.
+    pub a4: f64,
+    pub tunings: Vec<TuningSpec>,
+    /// Tuning table of each channel, 0 being standard tuning. Channels not
+    /// listed play the first loaded table.
+    pub channel_tunings: Vec<usize>,
+    /// Live notes held longer than this many seconds are released, in case
+    /// their note-off got lost. 0 disables the timeout.
+    pub stuck_note_timeout: f32,
+    /// Bars of count-in before real-time recording.
+    pub count_in: usize,
+    /// Groove of the sequencers and how much of it they play (0..1).
+    /// Project patterns can set their own.
+    pub swing: f32,
+    pub groove: Groove,
+    /// Seed of the random numbers of step probabilities. Live playing is
+    /// seeded from the clock if not given, renders with 0.
+    pub seed: Option<u64>,
+
+    pub headless: bool,
+    /// Render the project to this WAV file instead of running live.
+    pub render: Option<PathBuf>,
+    pub bars: usize,
+
+    /// Log level, optionally followed by per module levels, e.g.
+    /// `info,midi=debug`.
+    pub log_level: String,
+    pub diagnostics: bool,
+}
+
+/// A tuning table to load: a Scala scale file, or a number of equal steps
+/// per octave, with an optional Scala keyboard mapping.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TuningSpec {
+    pub scale: String,
+    #[serde(default)]
+    pub keymap: Option<PathBuf>,
+}
+
+/// What the command line asks the application to do.
+pub enum Command {
+    Run(Config),
+    ListAudioDevices,
+    ListMidiPorts,
+    Help(&'static str),
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            channel_count: CHANNEL_COUNT,
+            voice_count: VOICE_COUNT,
+            max_steps: MAX_STEPS,
+            sample_hz: SAMPLE_HZ,
+            buffer_size: None,
+            audio_device: None,
+            midi_port: None,
+            serial_port: None,
+            serial_baud: 115_200,
+            project: None,
+            autosave: None,
+            synth: SynthMode::Poly,
+            channel: 1,
+            allocation: Allocation::Oldest,
+            priority: Priority::Last,
+            legato: false,
+            glide: 0.0,
+            unison: 1,
+            detune: 0.1,
+            spread: 0.5,
+            bend_range: 2.0,
+            mpe: false,
+            mpe_bend_range: 48.0,
+            mod_wheel_vibrato: false,
+            a4: 440.0,
+            tunings: vec![],
+            channel_tunings: vec![],
+            stuck_note_timeout: 0.0,
+            count_in: 1,
+            swing: 0.0,
+            groove: Groove::Swing,
+            seed: None,
+            headless: false,
+            render: None,
+            bars: 4,
+            log_level: "info".to_string(),
+            diagnostics: false,
+        }
+    }
+}
+
+impl Config {
+    /// Read a config file.
+    pub fn from_file(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("can't read config file {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("invalid config file {}: {}", path.display(), e))
+    }
+
+    /// Parse command line arguments (without the program name), e.g.
+    /// `--channels 8 --voices 8 --steps 64`, on top of the config file.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+        let args: Vec<String> = args.into_iter().collect();
+
+        // these work whatever state the config file is in
+        for arg in args.iter() {
+            match arg.as_str() {
+                "--list-audio-devices" => return Ok(Command::ListAudioDevices),
+                "--list-midi-ports" => return Ok(Command::ListMidiPorts),
+                "--help" | "-h" => return Ok(Command::Help(USAGE)),
+                _ => {}
+            }
+        }
+
+        let mut config = match args.iter().position(|a| a == "--config") {
+            Some(i) => {
+                let path = args.get(i + 1).ok_or("missing value for --config")?;
+                Config::from_file(Path::new(path))?
+            }
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("missing value for {}", arg))
+            };
+            match arg.as_str() {
+                "--config" => {
+                    value()?;
+                }
+                "--channels" => config.channel_count = parse(&arg, &value()?)?,
+                "--voices" => config.voice_count = parse(&arg, &value()?)?,
+                "--steps" => config.max_steps = parse(&arg, &value()?)?,
+                "--sample-rate" => config.sample_hz = parse(&arg, &value()?)?,
+                "--buffer-size" => config.buffer_size = Some(parse(&arg, &value()?)?),
+                "--audio-device" => config.audio_device = Some(value()?),
+                "--midi-port" => config.midi_port = Some(value()?),
+                "--serial-port" => config.serial_port = Some(value()?),
+                "--serial-baud" => config.serial_baud = parse(&arg, &value()?)?,
+                "--project" => config.project = Some(PathBuf::from(value()?)),
+                "--autosave" => config.autosave = Some(PathBuf::from(value()?)),
+                "--synth" => {
+                    config.synth = match value()?.as_str() {
+                        "poly" => SynthMode::Poly,
+                        "mono" => SynthMode::Mono,
+                        other => return Err(format!("invalid value \"{}\" for --synth", other)),
+                    }
+                }
+                "--channel" => config.channel = parse(&arg, &value()?)?,
+                "--allocation" => {
+                    let name = value()?;
+                    config.allocation = Allocation::from_name(&name).ok_or_else(|| {
+                        format!("invalid value \"{}\" for --allocation", name)
+                    })?;
+                }
+                "--priority" => {
+                    let name = value()?;
+                    config.priority = Priority::from_name(&name).ok_or_else(|| {
+                        format!("invalid value \"{}\" for --priority", name)
+                    })?;
+                }
+                "--legato" => config.legato = true,
+                "--glide" => config.glide = parse(&arg, &value()?)?,
+                "--unison" => config.unison = parse(&arg, &value()?)?,
+                "--detune" => config.detune = parse(&arg, &value()?)?,
+                "--spread" => config.spread = parse(&arg, &value()?)?,
+                "--bend-range" => config.bend_range = parse(&arg, &value()?)?,
+                "--mpe" => config.mpe = true,
+                "--mpe-bend-range" => config.mpe_bend_range = parse(&arg, &value()?)?,
+                "--mod-wheel-vibrato" => config.mod_wheel_vibrato = true,
+                "--a4" => config.a4 = parse(&arg, &value()?)?,
+                "--tuning" => config.tunings.push(TuningSpec {
+                    scale: value()?,
+                    keymap: None,
+                }),
+                "--keymap" => {
+                    let keymap = PathBuf::from(value()?);
+                    config
+                        .tunings
+                        .last_mut()
+                        .ok_or("--keymap needs a --tuning before it")?
+                        .keymap = Some(keymap);
+                }
+                "--channel-tunings" => {
+                    config.channel_tunings = value()?
+                        .split(',')
+                        .map(|index| parse(&arg, index.trim()))
+                        .collect::<Result<_, _>>()?;
+                }
+                "--stuck-note-timeout" => config.stuck_note_timeout = parse(&arg, &value()?)?,
+                "--count-in" => config.count_in = parse(&arg, &value()?)?,
+                "--swing" => config.swing = parse(&arg, &value()?)?,
+                "--groove" => {
+                    let name = value()?;
+                    config.groove = Groove::from_name(&name).ok_or_else(|| {
+                        format!("invalid value \"{}\" for --groove", name)
+                    })?;
+                }
+                "--seed" => config.seed = Some(parse(&arg, &value()?)?),
+                "--headless" => config.headless = true,
+                "--render" => config.render = Some(PathBuf::from(value()?)),
+                "--bars" => config.bars = parse(&arg, &value()?)?,
+                "--log-level" => config.log_level = value()?,
+                "--diagnostics" => config.diagnostics = true,
+                _ => return Err(format!("unknown argument {}\n\n{}", arg, USAGE)),
+            }
+        }
+        config.validate()?;
+        Ok(Command::Run(config))
+    }
+
+    /// The stuck note timeout in nanoseconds, if enabled.
+    pub fn stuck_note_timeout_ns(&self) -> Option<u64> {
+        if self.stuck_note_timeout > 0.0 {
+            Some((self.stuck_note_timeout as f64 * 1e9) as u64)
+        } else {
+            None
+        }
+    }
+
+    pub fn unison(&self) -> Unison {
+        Unison {
+            voices: self.unison,
+            detune: self.detune,
+            spread: self.spread,
+        }
+    }
+
+    pub fn swing(&self) -> Swing {
+        Swing {
+            groove: self.groove,
+            amount: self.swing,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        // channel 0 takes live input, sequencers run on the others
+        if self.channel_count < 2 {
+            return Err("at least 2 channels are needed".to_string());
+        }
+        if self.voice_count == 0 {
+            return Err("at least 1 voice is needed".to_string());
+        }
+        if self.max_steps == 0 {
+            return Err("at least 1 step is needed".to_string());
+        }
+        if !(self.sample_hz > 0.0) {
+            return Err("sample rate must be positive".to_string());
+        }
+        if self.channel >= self.channel_count {
+            return Err(format!(
+                "channel {} doesn't exist, there are {} channels",
+                self.channel, self.channel_count
+            ));
+        }
+        if !(self.glide >= 0.0) {
+            return Err("glide time can't be negative".to_string());
+        }
+        if self.unison == 0 || self.unison > self.voice_count {
+            return Err(format!(
+                "unison must be between 1 and the voice count ({})",
+                self.voice_count
+            ));
+        }
+        if !(self.detune >= 0.0) {
+            return Err("detune can't be negative".to_string());
+        }
+        if !(self.spread >= 0.0 && self.spread <= 1.0) {
+            return Err("stereo spread must be between 0 and 1".to_string());
+        }
+        if !(self.bend_range >= 0.0 && self.mpe_bend_range >= 0.0) {
+            return Err("bend range can't be negative".to_string());
+        }
+        if !(self.a4 > 0.0) {
+            return Err("A4 frequency must be positive".to_string());
+        }
+        if self.channel_tunings.len() > self.channel_count {
+            return Err(format!(
+                "tunings given for {} channels, there are {}",
+                self.channel_tunings.len(),
+                self.channel_count
+            ));
+        }
+        if let Some(&index) = self.channel_tunings.iter().find(|&&i| i > self.tunings.len()) {
+            return Err(format!(
+                "tuning {} doesn't exist, there are {} tunings",
+                index,
+                self.tunings.len()
+            ));
+        }
+        if !(self.swing >= 0.0 && self.swing <= 1.0) {
+            return Err("swing must be between 0 and 1".to_string());
+        }
+        if !(self.stuck_note_timeout >= 0.0) {
+            return Err("stuck note timeout can't be negative".to_string());
+        }
+        for directive in self.log_level.split(',') {
+            let level = directive.rsplit('=').next().unwrap_or("");
+            match level.trim() {
+                "off" | "error" | "warn" | "info" | "debug" | "trace" => {}
+                _ => return Err(format!("invalid log level \"{}\"", directive)),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value \"{}\" for {}", value, arg))
+}
@@ -0,0 +1,521 @@
+//! The control thread owns the engine, the voice allocation and the
+//! sequencers. Input threads send it events over lock-free queues and wake
+//! it up, so a slow consumer never blocks the MIDI callback and a sequencer
+//! step never waits behind a lock.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+use synthesizer_io_core::queue::{Queue, Receiver, Sender};
+
+use crate::clock::Clock;
+use crate::diagnostics::Diagnostics;
+use crate::engine::Engine;
+use crate::input::CtrlEvent;
+use crate::midi::Midi;
+use crate::note::{NoteEvent, NoteModule};
+use crate::project::Project;
+use crate::sequencer::{RecordMode, Sequencer, SequencerStep, Swing, STEPS_PER_BAR};
+use crate::serial::Serial;
+
+/// Longest the control thread sleeps, so the worker's return queue is
+/// drained even when nothing is playing.
+const MAX_PARK: Duration = Duration::from_millis(100);
+
+/// How long the audio keeps running after the final note-offs, so releases
+/// fade out instead of being cut.
+const RELEASE_TAIL: Duration = Duration::from_millis(1000);
+
+/// Count-in clicks, played on the live channel, the first beat of each bar
+/// higher.
+const CLICK_NOTE: f32 = 84.0;
+const CLICK_DOWNBEAT: f32 = 96.0;
+const CLICK_VELOCITY: f32 = 100.0;
+
+/// Input for the control thread. Timestamps are taken by the producer when
+/// the event arrives, so time spent in the queue doesn't shift it.
+pub enum Event {
+    Midi(Vec<u8>, u64),
+    Serial(Vec<u8>, u64),
+}
+
+/// Sending end of one producer's queue.
+pub struct ControlSender {
+    tx: Sender<Event>,
+    thread: Thread,
+}
+
+impl ControlSender {
+    pub fn new(tx: Sender<Event>, thread: Thread) -> ControlSender {
+        ControlSender { tx, thread }
+    }
+
+    pub fn send(&self, event: Event) {
+        self.tx.send(event);
+        self.thread.unpark();
+    }
+}
+
+/// A sequencer with its place on the host clock. Each step is scheduled
+/// half a step before it's due, which is halfway through the step before:
+/// the held notes are recorded into that one first.
+struct Track {
+    sequencer: Sequencer,
+    // host time the next step is scheduled
+    next_due: u64,
+    // a step is playing, to record into
+    playing: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Transport {
+    Playing,
+    Stopped,
+    /// Clicking `beats` more beats before playing, `click` is the note
+    /// sounding.
+    CountIn { beats: usize, next_due: u64, click: Option<f32> },
+}
+
+pub struct Control {
+    engine: Engine,
+    note_module: NoteModule,
+    serial: Serial,
+    tracks: Vec<Track>,
+    // one queue per producer thread
+    queues: Vec<Receiver<Event>>,
+    diagnostics: Arc<Diagnostics>,
+    autosave: Option<PathBuf>,
+    // steps copied for pasting, on any channel
+    clipboard: Vec<SequencerStep>,
+    transport: Transport,
+    // recording into the selected channel
+    record: bool,
+    record_mode: RecordMode,
+    count_in_bars: usize,
+    // timing of the channels without their own
+    swing: Swing,
+    // notes of the chord being entered while stopped, until all keys are up
+    chord: Vec<(f32, f32)>,
+}
+
+impl Control {
+    pub fn new(
+        engine: Engine,
+        note_module: NoteModule,
+        sequencers: Vec<Sequencer>,
+        diagnostics: Arc<Diagnostics>,
+        autosave: Option<PathBuf>,
+    ) -> Control {
+        let now = Clock::host_now();
+        let tracks = sequencers
+            .into_iter()
+            .map(|sequencer| Track {
+                sequencer,
+                next_due: now,
+                playing: false,
+            })
+            .collect();
+        let mut control = Control {
+            engine,
+            note_module,
+            serial: Serial::new(),
+            tracks,
+            queues: Vec::new(),
+            diagnostics,
+            autosave,
+            clipboard: vec![],
+            transport: Transport::Stopped,
+            record: false,
+            record_mode: RecordMode::Overdub,
+            count_in_bars: 0,
+            swing: Swing::STRAIGHT,
+            chord: vec![],
+        };
+        control.start_tracks(now);
+        control
+    }
+
+    /// Bars of clicks before the sequencers start when recording.
+    pub fn set_count_in(&mut self, bars: usize) {
+        self.count_in_bars = bars;
+    }
+
+    /// Groove of the channels that don't have their own.
+    pub fn set_swing(&mut self, swing: Swing) {
+        self.swing = swing;
+    }
+
+    /// Add a queue for a producer thread. Wrap the sender in a
+    /// `ControlSender` once the control thread is running.
+    pub fn add_producer(&mut self) -> Sender<Event> {
+        let (tx, rx) = Queue::new();
+        self.queues.push(rx);
+        tx
+    }
+
+    /// Run on the current thread until `shutdown` is set, then stop the
+    /// sequencers, release all notes and save the project.
+    pub fn run(mut self, shutdown: &AtomicBool) {
+        while !shutdown.load(Ordering::SeqCst) {
+            self.poll_events();
+            self.run_count_in();
+            self.run_sequencers();
+            let now = self.engine.now();
+            self.note_module.release_stuck(&mut self.engine, now);
+            let backlog = self.engine.backlog();
+            self.diagnostics.set_backlog(backlog);
+            self.diagnostics.set_latency(self.engine.get_clock().latency_ns());
+
+            let now = Clock::host_now();
+            let next_due = match self.transport {
+                Transport::Playing => self.tracks.iter().map(|track| track.next_due).min(),
+                Transport::CountIn { next_due, .. } => Some(next_due),
+                Transport::Stopped => None,
+            };
+            // scheduled notes are due on the worker timeline, ahead of the
+            // host, and are sent ahead of that
+            let worker_now = self.engine.now();
+            let next_event = self
+                .tracks
+                .iter()
+                .filter_map(|track| {
+                    let lookahead = track.sequencer.lookahead_ns();
+                    track.sequencer.next_event().map(|at| at.saturating_sub(lookahead))
+                })
+                .min()
+                .map(|at| now + at.saturating_sub(worker_now));
+            let park = next_due
+                .into_iter()
+                .chain(next_event)
+                .min()
+                .map(|due| Duration::from_nanos(due.saturating_sub(now)))
+                .unwrap_or(MAX_PARK)
+                .min(MAX_PARK);
+            thread::park_timeout(park);
+        }
+        self.shut_down();
+    }
+
+    fn shut_down(&mut self) {
+        info!("Shutting down");
+        let ts = self.engine.now();
+        for track in self.tracks.iter_mut() {
+            track.sequencer.stop(&mut self.engine, &mut self.note_module, ts);
+        }
+        for channel in 0..self.note_module.channel_count() {
+            self.note_module.all_notes_off(&mut self.engine, channel, ts);
+        }
+
+        if let Some(ref path) = self.autosave {
+            let project = Project {
+                bpm: self.engine.get_tempo(),
+                patterns: self.tracks.iter().map(|t| t.sequencer.to_pattern()).collect(),
+            };
+            match project.save(path) {
+                Ok(()) => info!("Saved project to {}", path.display()),
+                Err(e) => error!("{}", e),
+            }
+        }
+        thread::sleep(RELEASE_TAIL);
+    }
+
+    fn poll_events(&mut self) {
+        for i in 0..self.queues.len() {
+            for event in self.queues[i].recv() {
+                self.handle_event(event);
+                self.step_entry();
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        let edits = match event {
+            Event::Midi(data, ts) => {
+                Midi::dispatch_midi(&mut self.note_module, &mut self.engine, &data, ts)
+            }
+            Event::Serial(data, ts) => {
+                self.serial
+                    .dispatch_serial(&mut self.note_module, &mut self.engine, &data, ts)
+            }
+        };
+        for edit in edits {
+            debug!("{:?}", edit);
+            if let Err(e) = self.handle_ctrl(edit) {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    fn handle_ctrl(&mut self, event: CtrlEvent) -> Result<(), String> {
+        match event {
+            CtrlEvent::Play => self.play(),
+            CtrlEvent::Continue => {
+                if self.transport == Transport::Stopped {
+                    self.resume_tracks(Clock::host_now());
+                }
+            }
+            CtrlEvent::Stop => self.stop(),
+            CtrlEvent::TogglePlay => match self.transport {
+                Transport::Stopped => self.play(),
+                _ => self.stop(),
+            },
+            CtrlEvent::ToggleRecord => self.set_record(!self.record),
+            CtrlEvent::SetRecordMode(mode) => {
+                info!("Record mode {:?}", mode);
+                self.record_mode = mode;
+            }
+            CtrlEvent::NextRecordMode => {
+                let index = (self.record_mode as usize + 1) % RecordMode::COUNT;
+                return self.handle_ctrl(CtrlEvent::SetRecordMode(RecordMode::from_index(index)));
+            }
+            CtrlEvent::SetCountIn(bars) => self.count_in_bars = bars,
+            CtrlEvent::Fill(fill) => {
+                for track in self.tracks.iter_mut() {
+                    track.sequencer.set_fill(fill);
+                }
+            }
+            CtrlEvent::ParamLock(lock) => self.engine.set_param_lock(lock),
+            CtrlEvent::SetSwing(amount) => self.swing.amount = amount.max(0.0).min(1.0),
+            CtrlEvent::SetGroove(groove) => self.swing.groove = groove,
+            CtrlEvent::SetChannelSwing { channel, amount } => {
+                sequencer(&mut self.tracks, channel)?.set_swing(amount);
+            }
+            CtrlEvent::SetChannelGroove { channel, groove } => {
+                sequencer(&mut self.tracks, channel)?.set_groove(groove);
+            }
+            CtrlEvent::MoveEditStep(offset) => {
+                let channel = self.engine.get_current_channel();
+                let length = sequencer(&mut self.tracks, channel)?.get_length() as isize;
+                let step = (self.engine.get_edit_step() as isize + offset).rem_euclid(length);
+                self.engine.set_edit_step(step as usize);
+            }
+            CtrlEvent::SelectEditStep(step) => {
+                let channel = self.engine.get_current_channel();
+                let length = sequencer(&mut self.tracks, channel)?.get_length();
+                self.engine.set_edit_step(step.min(length - 1));
+            }
+            CtrlEvent::SetNote { channel, step, note, velocity } => {
+                sequencer(&mut self.tracks, channel)?.set_note(step, note, velocity)?;
+            }
+            CtrlEvent::ToggleNote { channel, step, note, velocity } => {
+                sequencer(&mut self.tracks, channel)?.toggle_note(step, note, velocity)?;
+            }
+            CtrlEvent::ClearStep { channel, step } => {
+                sequencer(&mut self.tracks, channel)?.clear_step(step)?;
+            }
+            CtrlEvent::SetVelocity { channel, step, velocity } => {
+                sequencer(&mut self.tracks, channel)?.set_velocity(step, velocity)?;
+            }
+            CtrlEvent::SetGate { channel, step, gate } => {
+                sequencer(&mut self.tracks, channel)?.set_gate(step, gate)?;
+            }
+            CtrlEvent::SetTie { channel, step, tie } => {
+                sequencer(&mut self.tracks, channel)?.set_tie(step, tie)?;
+            }
+            CtrlEvent::SetSlide { channel, step, slide } => {
+                sequencer(&mut self.tracks, channel)?.set_slide(step, slide)?;
+            }
+            CtrlEvent::SetNudge { channel, step, nudge } => {
+                sequencer(&mut self.tracks, channel)?.set_nudge(step, nudge)?;
+            }
+            CtrlEvent::SetProbability { channel, step, probability } => {
+                sequencer(&mut self.tracks, channel)?.set_probability(step, probability)?;
+            }
+            CtrlEvent::SetCondition { channel, step, condition } => {
+                sequencer(&mut self.tracks, channel)?.set_condition(step, condition)?;
+            }
+            CtrlEvent::SetRatchet { channel, step, ratchet } => {
+                sequencer(&mut self.tracks, channel)?.set_ratchet(step, ratchet)?;
+            }
+            CtrlEvent::SetLock { channel, step, param, value } => {
+                sequencer(&mut self.tracks, channel)?.set_lock(step, param, value)?;
+            }
+            CtrlEvent::ClearLocks { channel, step } => {
+                sequencer(&mut self.tracks, channel)?.clear_locks(step)?;
+            }
+            CtrlEvent::Copy { channel, steps } => {
+                self.clipboard = sequencer(&mut self.tracks, channel)?.copy_steps(steps);
+            }
+            CtrlEvent::Paste { channel, step } => {
+                sequencer(&mut self.tracks, channel)?.paste_steps(step, &self.clipboard)?;
+            }
+            CtrlEvent::Shift { channel, steps, offset } => {
+                sequencer(&mut self.tracks, channel)?.shift_steps(steps, offset);
+            }
+            CtrlEvent::Transpose { channel, steps, semitones } => {
+                sequencer(&mut self.tracks, channel)?.transpose_steps(steps, semitones);
+            }
+            CtrlEvent::ClearChannel { channel } => {
+                sequencer(&mut self.tracks, channel)?.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn set_record(&mut self, record: bool) {
+        info!("Recording {}", if record { "armed" } else { "off" });
+        self.record = record;
+        self.chord.clear();
+    }
+
+    fn play(&mut self) {
+        let now = Clock::host_now();
+        if self.record && self.count_in_bars > 0 {
+            self.transport = Transport::CountIn {
+                beats: self.count_in_bars * STEPS_PER_BAR,
+                next_due: now,
+                click: None,
+            };
+        } else {
+            self.start_tracks(now);
+        }
+    }
+
+    /// Start every sequencer from its first step at host time `at`.
+    fn start_tracks(&mut self, at: u64) {
+        for track in self.tracks.iter_mut() {
+            track.sequencer.rewind();
+        }
+        self.resume_tracks(at);
+    }
+
+    /// Play every sequencer on from its next step at host time `at`.
+    fn resume_tracks(&mut self, at: u64) {
+        for track in self.tracks.iter_mut() {
+            // steps are scheduled half a step ahead
+            let half_step = (30.0 / track.sequencer.get_bpm() as f64 * 1e9) as u64;
+            track.next_due = at.saturating_sub(half_step);
+            track.playing = false;
+        }
+        self.transport = Transport::Playing;
+    }
+
+    fn stop(&mut self) {
+        let ts = self.engine.now();
+        if let Transport::CountIn { click: Some(note), .. } = self.transport {
+            self.click(note, false, ts);
+        }
+        for track in self.tracks.iter_mut() {
+            track.sequencer.stop(&mut self.engine, &mut self.note_module, ts);
+        }
+        self.transport = Transport::Stopped;
+    }
+
+    fn click(&mut self, note: f32, down: bool, ts: u64) {
+        let velocity = if down { CLICK_VELOCITY } else { 0.0 };
+        let event = NoteEvent { down, note, velocity, timestamp: ts };
+        self.note_module.note_event(&mut self.engine, event, 0);
+    }
+
+    /// Click through the count-in, a note per beat, then start playing.
+    fn run_count_in(&mut self) {
+        while let Transport::CountIn { beats, next_due, click } = self.transport {
+            if Clock::host_now() < next_due {
+                return;
+            }
+            let ts = self.engine.get_clock().at(next_due);
+            let half_step = (30.0 / self.engine.get_tempo() as f64 * 1e9) as u64;
+            if let Some(note) = click {
+                self.click(note, false, ts);
+                let next_due = next_due + half_step;
+                self.transport = Transport::CountIn { beats, next_due, click: None };
+            } else if beats == 0 {
+                self.start_tracks(next_due);
+            } else {
+                let note = if beats % STEPS_PER_BAR == 0 { CLICK_DOWNBEAT } else { CLICK_NOTE };
+                self.click(note, true, ts);
+                let next_due = next_due + half_step;
+                self.transport = Transport::CountIn { beats: beats - 1, next_due, click: Some(note) };
+            }
+        }
+    }
+
+    /// While stopped with recording armed, write each chord played into the
+    /// edit step, moving to the next step once all keys are up.
+    fn step_entry(&mut self) {
+        if !self.record || self.transport != Transport::Stopped {
+            return;
+        }
+        let held = held_notes(&self.note_module);
+        if !held.is_empty() {
+            for (note, velocity) in held {
+                if !self.chord.iter().any(|&(n, _)| n == note) {
+                    self.chord.push((note, velocity));
+                }
+            }
+            return;
+        }
+        if self.chord.is_empty() {
+            return;
+        }
+        let channel = self.engine.get_current_channel();
+        let step = self.engine.get_edit_step();
+        let mut edits = vec![CtrlEvent::ClearStep { channel, step }];
+        for (note, velocity) in self.chord.drain(..) {
+            edits.push(CtrlEvent::SetNote { channel, step, note, velocity });
+        }
+        edits.push(CtrlEvent::MoveEditStep(1));
+        for edit in edits {
+            if let Err(e) = self.handle_ctrl(edit) {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    fn run_sequencers(&mut self) {
+        if self.transport != Transport::Playing {
+            return;
+        }
+        let current_channel = self.engine.get_current_channel();
+        for track in self.tracks.iter_mut() {
+            loop {
+                let now = Clock::host_now();
+                if now < track.next_due {
+                    break;
+                }
+                let late_us = (now - track.next_due) / 1000;
+                trace!("channel {} step late by {}us", track.sequencer.get_channel(), late_us);
+                self.diagnostics.record_step(late_us);
+
+                let half_step = (30.0 / track.sequencer.get_bpm() as f64 * 1e9) as u64;
+                if track.playing && self.record && track.sequencer.get_channel() == current_channel {
+                    // halfway through the step playing
+                    let held = held_notes(&self.note_module);
+                    track.sequencer.record(self.record_mode, &held);
+                }
+                // timestamp of when the step is due, not when we got to it
+                let ts = self.engine.get_clock().at(track.next_due + half_step);
+                track.sequencer.tick(&mut self.engine, &mut self.note_module, ts, self.swing);
+                track.playing = true;
+
+                track.next_due += 2 * half_step;
+                // after a long stall skip ahead instead of playing catch up
+                if now > track.next_due + 4 * half_step {
+                    track.next_due = now;
+                }
+            }
+            let until = self.engine.now() + track.sequencer.lookahead_ns();
+            track.sequencer.run_due(&mut self.engine, &mut self.note_module, until);
+        }
+    }
+}
+
+fn sequencer(tracks: &mut [Track], channel: usize) -> Result<&mut Sequencer, String> {
+    tracks
+        .iter_mut()
+        .map(|track| &mut track.sequencer)
+        .find(|sequencer| sequencer.get_channel() == channel)
+        .ok_or_else(|| format!("channel {} has no sequencer", channel))
+}
+
+/// Notes held on the live channel, with their velocities.
+fn held_notes(note_module: &NoteModule) -> Vec<(f32, f32)> {
+    note_module
+        .get_voices(0)
+        .iter()
+        .filter_map(|voice| voice.note.map(|note| (note, voice.velocity)))
+        .collect()
+}
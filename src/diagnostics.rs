@@ -0,0 +1,154 @@
+//! Runtime statistics gathered from the real-time paths and reported
+//! periodically, so problems show up without logging every event.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use log::info;
+
+/// Counters shared between the threads. Everything is a relaxed atomic, so
+/// recording is cheap enough for the MIDI callback and the audio thread.
+pub struct Diagnostics {
+    // sequencer step lateness in microseconds
+    steps: AtomicU64,
+    jitter_sum: AtomicU64,
+    jitter_sum_sq: AtomicU64,
+    jitter_max: AtomicU64,
+
+    midi_events: AtomicU64,
+    xruns: AtomicU64,
+
+    // audio callback time relative to the buffer period, in permille
+    callbacks: AtomicU64,
+    load_sum: AtomicU64,
+    load_max: AtomicU64,
+    overruns: AtomicU64,
+    // messages sent to the worker and not returned yet
+    backlog: AtomicU64,
+    // delay from an event's timestamp to it being heard, in microseconds
+    latency_us: AtomicU64,
+
+    // running totals, never reset, so the log can mention every new xrun
+    // whether or not diagnostics mode is on
+    xruns_total: AtomicU64,
+    overruns_total: AtomicU64,
+}
+
+impl Diagnostics {
+    pub fn new() -> Diagnostics {
+        Diagnostics {
+            steps: AtomicU64::new(0),
+            jitter_sum: AtomicU64::new(0),
+            jitter_sum_sq: AtomicU64::new(0),
+            jitter_max: AtomicU64::new(0),
+            midi_events: AtomicU64::new(0),
+            xruns: AtomicU64::new(0),
+            callbacks: AtomicU64::new(0),
+            load_sum: AtomicU64::new(0),
+            load_max: AtomicU64::new(0),
+            overruns: AtomicU64::new(0),
+            backlog: AtomicU64::new(0),
+            latency_us: AtomicU64::new(0),
+            xruns_total: AtomicU64::new(0),
+            overruns_total: AtomicU64::new(0),
+        }
+    }
+
+    /// Record how late a sequencer step was handled.
+    pub fn record_step(&self, late_us: u64) {
+        self.steps.fetch_add(1, Ordering::Relaxed);
+        self.jitter_sum.fetch_add(late_us, Ordering::Relaxed);
+        self.jitter_sum_sq.fetch_add(late_us * late_us, Ordering::Relaxed);
+        self.jitter_max.fetch_max(late_us, Ordering::Relaxed);
+    }
+
+    pub fn record_midi_event(&self) {
+        self.midi_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a buffer the device played late or not at all.
+    pub fn record_xrun(&self) {
+        self.xruns.fetch_add(1, Ordering::Relaxed);
+        self.xruns_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record how long an audio callback took, as a fraction of the buffer
+    /// period. Above 1 the callback overran and the device will underrun.
+    pub fn record_callback(&self, load: f64) {
+        let permille = (load * 1000.0) as u64;
+        self.callbacks.fetch_add(1, Ordering::Relaxed);
+        self.load_sum.fetch_add(permille, Ordering::Relaxed);
+        self.load_max.fetch_max(permille, Ordering::Relaxed);
+        if load > 1.0 {
+            self.overruns.fetch_add(1, Ordering::Relaxed);
+            self.overruns_total.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Record the number of messages queued to the worker and not yet
+    /// returned by it.
+    pub fn set_backlog(&self, backlog: u64) {
+        self.backlog.store(backlog, Ordering::Relaxed);
+    }
+
+    /// Record the output latency, see `Clock::latency_ns`.
+    pub fn set_latency(&self, latency_ns: u64) {
+        self.latency_us.store(latency_ns / 1000, Ordering::Relaxed);
+    }
+
+    /// Xruns and callback overruns since startup.
+    pub fn audio_problems(&self) -> (u64, u64) {
+        (
+            self.xruns_total.load(Ordering::Relaxed),
+            self.overruns_total.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Log the statistics gathered over `period` and reset them.
+    pub fn report(&self, period: Duration) {
+        let steps = self.steps.swap(0, Ordering::Relaxed);
+        let sum = self.jitter_sum.swap(0, Ordering::Relaxed) as f64;
+        let sum_sq = self.jitter_sum_sq.swap(0, Ordering::Relaxed) as f64;
+        let max = self.jitter_max.swap(0, Ordering::Relaxed);
+        let midi_events = self.midi_events.swap(0, Ordering::Relaxed);
+        let xruns = self.xruns.swap(0, Ordering::Relaxed);
+        let callbacks = self.callbacks.swap(0, Ordering::Relaxed);
+        let load_sum = self.load_sum.swap(0, Ordering::Relaxed);
+        let load_max = self.load_max.swap(0, Ordering::Relaxed);
+        let overruns = self.overruns.swap(0, Ordering::Relaxed);
+        let backlog = self.backlog.load(Ordering::Relaxed);
+        let latency_us = self.latency_us.load(Ordering::Relaxed);
+
+        let (mean, std_dev) = if steps > 0 {
+            let mean = sum / steps as f64;
+            (mean, (sum_sq / steps as f64 - mean * mean).max(0.0).sqrt())
+        } else {
+            (0.0, 0.0)
+        };
+        info!(
+            "steps {} jitter mean {:.0}us sd {:.0}us max {}us, midi {:.1} events/s, xruns {}",
+            steps,
+            mean,
+            std_dev,
+            max,
+            midi_events as f64 / period.as_secs_f64(),
+            xruns
+        );
+        info!(
+            "audio load mean {:.1}% max {:.1}%, overruns {}, worker backlog {} messages, latency {}us",
+            load_sum as f64 / 10.0 / callbacks.max(1) as f64,
+            load_max as f64 / 10.0,
+            overruns,
+            backlog,
+            latency_us
+        );
+    }
+}
+
+/// Report the statistics every `period`. Doesn't return.
+pub fn run_reporter(diagnostics: std::sync::Arc<Diagnostics>, period: Duration) {
+    loop {
+        std::thread::sleep(period);
+        diagnostics.report(period);
+    }
+}
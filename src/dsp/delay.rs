@@ -0,0 +1,104 @@
+//! Stereo send delay.
+
+use std::any::Any;
+use std::f32::consts::PI;
+
+use synthesizer_io_core::module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+
+/// Longest delay time supported, in seconds.
+pub const MAX_DELAY_SECONDS: f32 = 2.0;
+
+/// A stereo delay with filtered feedback and an optional ping-pong mode.
+///
+/// Buffer inputs are the left and right send, outputs the left and right
+/// return. Control inputs are delay time (seconds), feedback (0..1),
+/// feedback low-pass cutoff (log2 Hz), ping-pong (on when above 0.5) and
+/// the linear return level.
+pub struct Delay {
+    sample_rate: f32,
+    lines: [Vec<f32>; 2],
+    write_pos: usize,
+    // one pole low-pass state of each feedback path
+    lp: [f32; 2],
+    time: f32,
+}
+
+impl Delay {
+    pub fn new(sample_rate: f32) -> Delay {
+        let len = (sample_rate * MAX_DELAY_SECONDS) as usize + 2;
+        Delay {
+            sample_rate,
+            lines: [vec![0.0; len], vec![0.0; len]],
+            write_pos: 0,
+            lp: [0.0; 2],
+            time: 0.0,
+        }
+    }
+
+    fn read(line: &[f32], write_pos: usize, delay: f32) -> f32 {
+        let len = line.len();
+        let pos = write_pos as f32 + len as f32 - delay;
+        let i = pos as usize;
+        let frac = pos - i as f32;
+        let a = line[i % len];
+        let b = line[(i + 1) % len];
+        a + frac * (b - a)
+    }
+}
+
+impl Module for Delay {
+    fn n_bufs_out(&self) -> usize {
+        2
+    }
+
+    fn to_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn process(
+        &mut self,
+        control_in: &[f32],
+        _control_out: &mut [f32],
+        buf_in: &[&Buffer],
+        buf_out: &mut [Buffer],
+    ) {
+        let max_delay = (self.lines[0].len() - 2) as f32;
+        let target = (control_in[0] * self.sample_rate).max(1.0).min(max_delay);
+        let feedback = control_in[1].max(0.0).min(0.98);
+        let freq = control_in[2].exp2().min(self.sample_rate * 0.45);
+        let coeff = 1.0 - (-2.0 * PI * freq / self.sample_rate).exp();
+        let ping_pong = control_in[3] > 0.5;
+        let level = control_in[4].max(0.0);
+
+        if self.time == 0.0 {
+            self.time = target;
+        }
+        let step = (target - self.time) * (1.0 / N_SAMPLES_PER_CHUNK as f32);
+
+        let in_l = buf_in[0].get();
+        let in_r = buf_in[1].get();
+        let (out_l, out_r) = buf_out.split_at_mut(1);
+        let out_l = out_l[0].get_mut();
+        let out_r = out_r[0].get_mut();
+        for i in 0..N_SAMPLES_PER_CHUNK {
+            self.time += step;
+            let wet_l = Delay::read(&self.lines[0], self.write_pos, self.time);
+            let wet_r = Delay::read(&self.lines[1], self.write_pos, self.time);
+            self.lp[0] += coeff * (wet_l - self.lp[0]);
+            self.lp[1] += coeff * (wet_r - self.lp[1]);
+
+            let (write_l, write_r) = if ping_pong {
+                // feed the mono send into the left line and cross the feedback
+                ((in_l[i] + in_r[i]) * 0.5 + feedback * self.lp[1], feedback * self.lp[0])
+            } else {
+                (in_l[i] + feedback * self.lp[0], in_r[i] + feedback * self.lp[1])
+            };
+            self.lines[0][self.write_pos] = write_l;
+            self.lines[1][self.write_pos] = write_r;
+            self.write_pos = (self.write_pos + 1) % self.lines[0].len();
+
+            out_l[i] = wet_l * level;
+            out_r[i] = wet_r * level;
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! Per-voice pitch bend, mod wheel and pressure.
+
+use std::any::Any;
+use std::f32::consts::PI;
+
+use synthesizer_io_core::module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+
+/// Vibrato rate of the mod wheel in Hz.
+const VIBRATO_HZ: f32 = 5.5;
+
+/// Vibrato depth with the mod wheel fully up, in semitones.
+const VIBRATO_DEPTH: f32 = 0.5;
+
+/// Turns the performance controls of a channel and of one voice into a
+/// pitch offset and a cutoff for that voice.
+///
+/// Control inputs are the voice cutoff (log2 Hz), the channel bend (-1..1)
+/// and bend range (semitones), the voice bend and bend range, the mod
+/// wheel (0..1), the channel and voice pressure (0..1), the voice slide
+/// (0..1) and the cutoff amount of pressure and slide (octaves). Control
+/// outputs are the pitch offset (semitones) and the cutoff (log2 Hz).
+///
+/// The mod wheel adds vibrato. Pressure, the larger of the channel and voice
+/// ones, and slide both open the filter.
+pub struct Expression {
+    chunk_seconds: f32,
+    phase: f32,
+}
+
+impl Expression {
+    pub fn new(sample_rate: f32) -> Expression {
+        Expression {
+            chunk_seconds: N_SAMPLES_PER_CHUNK as f32 / sample_rate,
+            phase: 0.0,
+        }
+    }
+}
+
+impl Module for Expression {
+    fn n_ctrl_out(&self) -> usize {
+        2
+    }
+
+    fn to_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn process(
+        &mut self,
+        control_in: &[f32],
+        control_out: &mut [f32],
+        _buf_in: &[&Buffer],
+        _buf_out: &mut [Buffer],
+    ) {
+        let bend = control_in[1] * control_in[2] + control_in[3] * control_in[4];
+
+        self.phase = (self.phase + self.chunk_seconds * VIBRATO_HZ).fract();
+        let vibrato = (2.0 * PI * self.phase).sin() * control_in[5].max(0.0) * VIBRATO_DEPTH;
+
+        let pressure = control_in[6].max(control_in[7]).max(0.0);
+        let brightness = (pressure + control_in[8].max(0.0)) * control_in[9];
+
+        control_out[0] = bend + vibrato;
+        control_out[1] = (control_in[0] + brightness).min(22_000f32.log2());
+    }
+}
@@ -0,0 +1,178 @@
+//! Multi-mode voice filter.
+
+use std::any::Any;
+use std::f32::consts::PI;
+
+use synthesizer_io_core::module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+
+/// Number of samples used to crossfade between modes when switching.
+const CROSSFADE_SAMPLES: usize = 4 * N_SAMPLES_PER_CHUNK;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FilterMode {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+    Ladder,
+}
+
+impl FilterMode {
+    pub const COUNT: usize = 5;
+
+    pub fn from_index(index: usize) -> FilterMode {
+        match index {
+            0 => FilterMode::LowPass,
+            1 => FilterMode::HighPass,
+            2 => FilterMode::BandPass,
+            3 => FilterMode::Notch,
+            _ => FilterMode::Ladder,
+        }
+    }
+
+    /// Pick a mode from a 0..1 controller value, giving each mode an equal
+    /// share of the range.
+    pub fn from_ctrl(value: f32) -> FilterMode {
+        let index = (value.max(0.0) * FilterMode::COUNT as f32) as usize;
+        FilterMode::from_index(index.min(FilterMode::COUNT - 1))
+    }
+
+    /// The value to send to the mode control of a `Filter`.
+    pub fn to_ctrl(self) -> f32 {
+        self as usize as f32
+    }
+}
+
+/// A filter with selectable response.
+///
+/// Control inputs are cutoff (log2 Hz), resonance (0..1), mode (see
+/// `FilterMode::to_ctrl`) and drive (0..1, ladder only). Both the state
+/// variable and the ladder core are run continuously, so switching modes is
+/// a short crossfade between their outputs rather than a jump.
+pub struct Filter {
+    sample_rate: f32,
+    mode: FilterMode,
+    // blend of the modes being faded out of, so a switch during a crossfade
+    // starts from what is heard
+    fade_from: [f32; FilterMode::COUNT],
+    fade: usize,
+
+    // state variable filter integrators
+    ic1eq: f32,
+    ic2eq: f32,
+
+    // ladder stages and last output for the feedback path
+    stages: [f32; 4],
+    ladder_out: f32,
+}
+
+impl Filter {
+    pub fn new(sample_rate: f32) -> Filter {
+        Filter {
+            sample_rate,
+            mode: FilterMode::LowPass,
+            fade_from: [0.0; FilterMode::COUNT],
+            fade: 0,
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+            stages: [0.0; 4],
+            ladder_out: 0.0,
+        }
+    }
+
+    /// How much of each mode's output is heard at this point of the fade.
+    fn weights(&self) -> [f32; FilterMode::COUNT] {
+        let t = self.fade as f32 * (1.0 / CROSSFADE_SAMPLES as f32);
+        let mut weights = [0.0; FilterMode::COUNT];
+        for (i, w) in weights.iter_mut().enumerate() {
+            let target = if i == self.mode as usize { 1.0 } else { 0.0 };
+            *w = target + t * (self.fade_from[i] - target);
+        }
+        weights
+    }
+}
+
+impl Module for Filter {
+    fn n_bufs_out(&self) -> usize {
+        1
+    }
+
+    fn to_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old) = old.to_any().downcast_ref::<Filter>() {
+            self.mode = old.mode;
+            self.fade_from = old.fade_from;
+            self.fade = old.fade;
+            self.ic1eq = old.ic1eq;
+            self.ic2eq = old.ic2eq;
+            self.stages = old.stages;
+            self.ladder_out = old.ladder_out;
+        }
+    }
+
+    fn process(
+        &mut self,
+        control_in: &[f32],
+        _control_out: &mut [f32],
+        buf_in: &[&Buffer],
+        buf_out: &mut [Buffer],
+    ) {
+        let freq = control_in[0].exp2().max(10.0).min(self.sample_rate * 0.45);
+        let reso = control_in[1].max(0.0).min(0.995);
+        let mode = FilterMode::from_index(control_in[2].max(0.0).round() as usize);
+        let drive = 1.0 + 9.0 * control_in[3].max(0.0).min(1.0);
+
+        if mode != self.mode {
+            self.fade_from = self.weights();
+            self.mode = mode;
+            self.fade = CROSSFADE_SAMPLES;
+        }
+
+        let g = (PI * freq / self.sample_rate).tan();
+
+        // state variable coefficients
+        let k = 2.0 - 2.0 * reso;
+        let a1 = 1.0 / (1.0 + g * (g + k));
+        let a2 = g * a1;
+        let a3 = g * a2;
+
+        // ladder coefficients
+        let big_g = g / (1.0 + g);
+        let feedback = 4.0 * reso;
+        let makeup = 1.0 + 0.5 * feedback;
+
+        let input = buf_in[0].get();
+        let out = buf_out[0].get_mut();
+        for i in 0..N_SAMPLES_PER_CHUNK {
+            let v0 = input[i];
+
+            let v3 = v0 - self.ic2eq;
+            let v1 = a1 * self.ic1eq + a2 * v3;
+            let v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3;
+            self.ic1eq = 2.0 * v1 - self.ic1eq;
+            self.ic2eq = 2.0 * v2 - self.ic2eq;
+            let high = v0 - k * v1 - v2;
+
+            let mut u = (drive * (v0 - feedback * self.ladder_out)).tanh();
+            for s in self.stages.iter_mut() {
+                let v = (u - *s) * big_g;
+                let y = v + *s;
+                *s = y + v;
+                u = y;
+            }
+            self.ladder_out = u;
+
+            let outputs = [v2, high, v1, v2 + high, u * makeup];
+            out[i] = if self.fade > 0 {
+                let weights = self.weights();
+                self.fade -= 1;
+                weights.iter().zip(outputs.iter()).map(|(w, o)| w * o).sum()
+            } else {
+                outputs[self.mode as usize]
+            };
+        }
+    }
+}
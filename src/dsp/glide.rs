@@ -0,0 +1,73 @@
+//! Note pitch with portamento.
+
+use std::any::Any;
+
+use synthesizer_io_core::module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+
+/// Turns notes into a pitch control (log2 Hz), like `modules::NotePitch`,
+/// but slides to each new note instead of jumping.
+///
+/// Control inputs are the glide time in seconds, the time constant of the
+/// approach to the new pitch, a detune offset and a bend offset, both in
+/// semitones. At a glide time of 0 the pitch jumps. The first note always
+/// jumps, there's nothing to slide from.
+pub struct Glide {
+    chunk_seconds: f32,
+    pitch: Option<f32>,
+    target: f32,
+}
+
+impl Glide {
+    pub fn new(sample_rate: f32) -> Glide {
+        Glide {
+            chunk_seconds: N_SAMPLES_PER_CHUNK as f32 / sample_rate,
+            pitch: None,
+            target: 0.0,
+        }
+    }
+
+    /// Pitch of a MIDI note number, in log2 Hz.
+    pub fn note_pitch(midi_num: f32) -> f32 {
+        midi_num * (1.0 / 12.0) + (440f32.log2() - 69.0 / 12.0)
+    }
+}
+
+impl Module for Glide {
+    fn n_ctrl_out(&self) -> usize {
+        1
+    }
+
+    fn to_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn handle_note(&mut self, midi_num: f32, _velocity: f32, on: bool) {
+        if on {
+            self.target = Glide::note_pitch(midi_num);
+            if self.pitch.is_none() {
+                self.pitch = Some(self.target);
+            }
+        }
+    }
+
+    fn process(
+        &mut self,
+        control_in: &[f32],
+        control_out: &mut [f32],
+        _buf_in: &[&Buffer],
+        _buf_out: &mut [Buffer],
+    ) {
+        let time = control_in[0];
+        if let Some(pitch) = self.pitch {
+            let pitch = if time > 0.0 {
+                let coef = 1.0 - (-self.chunk_seconds / time).exp();
+                pitch + (self.target - pitch) * coef
+            } else {
+                self.target
+            };
+            self.pitch = Some(pitch);
+        }
+        let offset = (control_in[1] + control_in[2]) * (1.0 / 12.0);
+        control_out[0] = self.pitch.unwrap_or(self.target) + offset;
+    }
+}
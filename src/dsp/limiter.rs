@@ -0,0 +1,178 @@
+//! Master bus dynamics and metering.
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use synthesizer_io_core::module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+
+/// How far the limiter looks ahead, in seconds. This is also the latency it
+/// adds to the output.
+const LOOKAHEAD_SECONDS: f32 = 0.005;
+
+/// Fraction of the ceiling where the soft clipper starts to bend. The
+/// limiter holds peaks here, so only what it lets through gets clipped.
+const KNEE: f32 = 0.8;
+
+/// Output levels published by the master bus for the application to read.
+///
+/// Levels are linear and held at their maximum until taken.
+pub struct Meter {
+    peak: [AtomicU32; 2],
+    min_gain: AtomicU32,
+    overs: AtomicUsize,
+}
+
+/// A snapshot of the meter, see `Meter::take`.
+#[derive(Clone, Copy, Debug)]
+pub struct MeterReading {
+    pub peak: [f32; 2],
+    /// Lowest gain applied by the limiter.
+    pub min_gain: f32,
+    /// Samples that would have exceeded the ceiling without the limiter.
+    pub overs: usize,
+}
+
+impl Meter {
+    pub fn new() -> Meter {
+        Meter {
+            peak: [AtomicU32::new(0), AtomicU32::new(0)],
+            min_gain: AtomicU32::new(1f32.to_bits()),
+            overs: AtomicUsize::new(0),
+        }
+    }
+
+    /// Read the levels since the last call and reset them.
+    pub fn take(&self) -> MeterReading {
+        MeterReading {
+            peak: [
+                f32::from_bits(self.peak[0].swap(0, Ordering::Relaxed)),
+                f32::from_bits(self.peak[1].swap(0, Ordering::Relaxed)),
+            ],
+            min_gain: f32::from_bits(self.min_gain.swap(1f32.to_bits(), Ordering::Relaxed)),
+            overs: self.overs.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    // The bit patterns of non-negative floats sort like the floats, so the
+    // integer min and max work on them directly.
+    fn publish(&self, peak: [f32; 2], min_gain: f32, overs: usize) {
+        self.peak[0].fetch_max(peak[0].to_bits(), Ordering::Relaxed);
+        self.peak[1].fetch_max(peak[1].to_bits(), Ordering::Relaxed);
+        self.min_gain.fetch_min(min_gain.max(0.0).to_bits(), Ordering::Relaxed);
+        self.overs.fetch_add(overs, Ordering::Relaxed);
+    }
+}
+
+/// Master dynamics: a look-ahead peak limiter holding peaks at the knee,
+/// followed by a soft clipper that keeps anything the limiter lets through
+/// below the ceiling.
+///
+/// Buffer inputs and outputs are a stereo pair. Control inputs are the
+/// ceiling (dBFS) and the release time (seconds).
+pub struct Limiter {
+    sample_rate: f32,
+    lookahead: usize,
+    delay: VecDeque<(f32, f32)>,
+    // (sample index, target gain), increasing gains from front to back
+    targets: VecDeque<(usize, f32)>,
+    index: usize,
+    gain: f32,
+    meter: Arc<Meter>,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: f32, meter: Arc<Meter>) -> Limiter {
+        let lookahead = ((sample_rate * LOOKAHEAD_SECONDS) as usize).max(1);
+        // room for the sample pushed before one is popped, so the audio
+        // thread never grows it
+        let mut delay = VecDeque::with_capacity(lookahead + 1);
+        delay.extend((0..lookahead).map(|_| (0.0, 0.0)));
+        Limiter {
+            sample_rate,
+            lookahead,
+            delay,
+            targets: VecDeque::with_capacity(lookahead + 2),
+            index: 0,
+            gain: 1.0,
+            meter,
+        }
+    }
+
+    fn soft_clip(x: f32, ceiling: f32) -> f32 {
+        let knee = KNEE * ceiling;
+        let magnitude = x.abs();
+        if magnitude <= knee {
+            x
+        } else {
+            let range = ceiling - knee;
+            (knee + range * ((magnitude - knee) / range).tanh()).copysign(x)
+        }
+    }
+}
+
+impl Module for Limiter {
+    fn n_bufs_out(&self) -> usize {
+        2
+    }
+
+    fn to_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn process(
+        &mut self,
+        control_in: &[f32],
+        _control_out: &mut [f32],
+        buf_in: &[&Buffer],
+        buf_out: &mut [Buffer],
+    ) {
+        let ceiling = 10f32.powf(control_in[0].min(0.0) * (1.0 / 20.0));
+        let release = control_in[1].max(0.001);
+        let attack_coeff = 1.0 - (-(100f32.ln()) / self.lookahead as f32).exp();
+        let release_coeff = 1.0 - (-1.0 / (release * self.sample_rate)).exp();
+
+        let in_l = buf_in[0].get();
+        let in_r = buf_in[1].get();
+        let (out_l, out_r) = buf_out.split_at_mut(1);
+        let out_l = out_l[0].get_mut();
+        let out_r = out_r[0].get_mut();
+
+        let mut peak = [0f32; 2];
+        let mut min_gain = 1f32;
+        let mut overs = 0;
+        let knee = KNEE * ceiling;
+        for i in 0..N_SAMPLES_PER_CHUNK {
+            let level = in_l[i].abs().max(in_r[i].abs());
+            if level > ceiling {
+                overs += 1;
+            }
+            let target = if level > knee { knee / level } else { 1.0 };
+
+            // sliding minimum of the target gain over the look-ahead window
+            while self.targets.back().map_or(false, |&(_, t)| t >= target) {
+                self.targets.pop_back();
+            }
+            self.targets.push_back((self.index, target));
+            // the window runs from the sample output next, `lookahead` back
+            while self.targets.front().map_or(false, |&(ix, _)| ix + self.lookahead < self.index) {
+                self.targets.pop_front();
+            }
+            self.index += 1;
+            let window_min = self.targets.front().map_or(1.0, |&(_, t)| t);
+
+            let coeff = if window_min < self.gain { attack_coeff } else { release_coeff };
+            self.gain += (window_min - self.gain) * coeff;
+            min_gain = min_gain.min(self.gain);
+
+            self.delay.push_back((in_l[i], in_r[i]));
+            let (l, r) = self.delay.pop_front().unwrap_or((0.0, 0.0));
+            out_l[i] = Limiter::soft_clip(l * self.gain, ceiling);
+            out_r[i] = Limiter::soft_clip(r * self.gain, ceiling);
+            peak[0] = peak[0].max(out_l[i].abs());
+            peak[1] = peak[1].max(out_r[i].abs());
+        }
+        self.meter.publish(peak, min_gain, overs);
+    }
+}
@@ -0,0 +1,215 @@
+//! Channel mixer producing the stereo output and effect sends.
+
+use std::any::Any;
+use std::f32::consts::{FRAC_PI_4, SQRT_2};
+
+use synthesizer_io_core::module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+
+/// Number of control inputs used by each channel strip.
+pub const STRIP_CONTROLS: usize = 6;
+
+/// Number of stereo buses produced: the dry mix, the delay send and the
+/// reverb send.
+pub const BUSES: usize = 3;
+
+/// Mixes stereo channels down to stereo buses. Channel buffers are
+/// left/right pairs.
+///
+/// Each channel takes `STRIP_CONTROLS` control inputs: linear gain, balance
+/// (-1..1), mute and solo (both on when above 0.5), then the
+/// delay and reverb send levels. The last control input is the linear master
+/// gain, which also scales the sends. If any channel is soloed, only soloed
+/// channels are heard.
+///
+/// The outputs are left/right pairs of the dry mix, the delay send and the
+/// reverb send. Sends are taken after the channel gain and pan.
+pub struct Mixer {
+    channels: usize,
+    // per channel and bus left and right gain of the previous chunk
+    last_gains: Vec<[(f32, f32); BUSES]>,
+}
+
+impl Mixer {
+    pub fn new(channels: usize) -> Mixer {
+        Mixer {
+            channels,
+            last_gains: vec![[(0.0, 0.0); BUSES]; channels],
+        }
+    }
+
+    /// Equal power pan law, returning the left and right gain.
+    pub fn pan_gains(pan: f32) -> (f32, f32) {
+        let angle = (pan.max(-1.0).min(1.0) + 1.0) * FRAC_PI_4;
+        (angle.cos(), angle.sin())
+    }
+
+    /// Balance of a stereo signal: the center leaves both sides untouched,
+    /// turning it attenuates the other side with the equal power law.
+    pub fn balance_gains(balance: f32) -> (f32, f32) {
+        let (l, r) = Mixer::pan_gains(balance);
+        ((l * SQRT_2).min(1.0), (r * SQRT_2).min(1.0))
+    }
+}
+
+impl Module for Mixer {
+    fn n_bufs_out(&self) -> usize {
+        2 * BUSES
+    }
+
+    fn to_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old) = old.to_any().downcast_ref::<Mixer>() {
+            for (new, old) in self.last_gains.iter_mut().zip(old.last_gains.iter()) {
+                *new = *old;
+            }
+        }
+    }
+
+    fn process(
+        &mut self,
+        control_in: &[f32],
+        _control_out: &mut [f32],
+        buf_in: &[&Buffer],
+        buf_out: &mut [Buffer],
+    ) {
+        let master = control_in[self.channels * STRIP_CONTROLS].max(0.0);
+        let any_solo = (0..self.channels).any(|c| control_in[c * STRIP_CONTROLS + 3] > 0.5);
+
+        for buf in buf_out.iter_mut() {
+            buf.set_zero();
+        }
+
+        for c in 0..self.channels {
+            let strip = &control_in[c * STRIP_CONTROLS..(c + 1) * STRIP_CONTROLS];
+            let muted = strip[2] > 0.5;
+            let soloed = strip[3] > 0.5;
+            let audible = !muted && (!any_solo || soloed);
+
+            let gain = if audible { strip[0].max(0.0) * master } else { 0.0 };
+            let (pan_l, pan_r) = Mixer::balance_gains(strip[1]);
+            let levels = [1.0, strip[4].max(0.0), strip[5].max(0.0)];
+            let input_l = buf_in[2 * c].get();
+            let input_r = buf_in[2 * c + 1].get();
+
+            for bus in 0..BUSES {
+                let target_l = gain * levels[bus] * pan_l;
+                let target_r = gain * levels[bus] * pan_r;
+                let (mut gl, mut gr) = self.last_gains[c][bus];
+                self.last_gains[c][bus] = (target_l, target_r);
+                if gl == 0.0 && gr == 0.0 && target_l == 0.0 && target_r == 0.0 {
+                    continue;
+                }
+                let step_l = (target_l - gl) * (1.0 / N_SAMPLES_PER_CHUNK as f32);
+                let step_r = (target_r - gr) * (1.0 / N_SAMPLES_PER_CHUNK as f32);
+                let (left, right) = buf_out[2 * bus..2 * bus + 2].split_at_mut(1);
+                let left = left[0].get_mut();
+                let right = right[0].get_mut();
+                for i in 0..N_SAMPLES_PER_CHUNK {
+                    gl += step_l;
+                    gr += step_r;
+                    left[i] += input_l[i] * gl;
+                    right[i] += input_r[i] * gr;
+                }
+            }
+        }
+    }
+}
+
+/// Pans the mono voices of a channel into a stereo pair.
+///
+/// Takes one buffer and one pan control (-1..1, equal power) per voice. Pan
+/// changes are ramped across the chunk, as a voice may still be releasing
+/// when it's moved for its next note.
+pub struct VoiceMix {
+    last_gains: Vec<(f32, f32)>,
+}
+
+impl VoiceMix {
+    pub fn new(voices: usize) -> VoiceMix {
+        VoiceMix {
+            last_gains: vec![Mixer::pan_gains(0.0); voices],
+        }
+    }
+}
+
+impl Module for VoiceMix {
+    fn n_bufs_out(&self) -> usize {
+        2
+    }
+
+    fn to_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn process(
+        &mut self,
+        control_in: &[f32],
+        _control_out: &mut [f32],
+        buf_in: &[&Buffer],
+        buf_out: &mut [Buffer],
+    ) {
+        for buf in buf_out.iter_mut() {
+            buf.set_zero();
+        }
+        let (left, right) = buf_out.split_at_mut(1);
+        let left = left[0].get_mut();
+        let right = right[0].get_mut();
+        for (v, input) in buf_in.iter().enumerate() {
+            let (target_l, target_r) = Mixer::pan_gains(control_in[v]);
+            let (mut gl, mut gr) = self.last_gains[v];
+            self.last_gains[v] = (target_l, target_r);
+            let step_l = (target_l - gl) * (1.0 / N_SAMPLES_PER_CHUNK as f32);
+            let step_r = (target_r - gr) * (1.0 / N_SAMPLES_PER_CHUNK as f32);
+            let input = input.get();
+            for i in 0..N_SAMPLES_PER_CHUNK {
+                gl += step_l;
+                gr += step_r;
+                left[i] += input[i] * gl;
+                right[i] += input[i] * gr;
+            }
+        }
+    }
+}
+
+/// Sums stereo pairs of buffer inputs into a single stereo output.
+pub struct StereoSum;
+
+impl StereoSum {
+    pub fn new() -> StereoSum {
+        StereoSum
+    }
+}
+
+impl Module for StereoSum {
+    fn n_bufs_out(&self) -> usize {
+        2
+    }
+
+    fn to_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn process(
+        &mut self,
+        _control_in: &[f32],
+        _control_out: &mut [f32],
+        buf_in: &[&Buffer],
+        buf_out: &mut [Buffer],
+    ) {
+        for (side, out) in buf_out.iter_mut().take(2).enumerate() {
+            let out = out.get_mut();
+            for i in 0..N_SAMPLES_PER_CHUNK {
+                out[i] = 0.0;
+            }
+            for input in buf_in.iter().skip(side).step_by(2) {
+                let input = input.get();
+                for i in 0..N_SAMPLES_PER_CHUNK {
+                    out[i] += input[i];
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,22 @@
+//! Signal processing modules used by the engine graph in addition to the
+//! ones provided by `synthesizer_io_core::modules`.
+
+mod delay;
+mod expression;
+mod filter;
+mod glide;
+mod limiter;
+mod mixer;
+mod reverb;
+mod vca;
+mod velocity;
+
+pub use self::delay::{Delay, MAX_DELAY_SECONDS};
+pub use self::expression::Expression;
+pub use self::filter::{Filter, FilterMode};
+pub use self::glide::Glide;
+pub use self::limiter::{Limiter, Meter};
+pub use self::mixer::{Mixer, StereoSum, VoiceMix};
+pub use self::reverb::Reverb;
+pub use self::vca::Vca;
+pub use self::velocity::Velocity;
@@ -0,0 +1,118 @@
+//! Stereo send reverb.
+
+use std::any::Any;
+
+use synthesizer_io_core::module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+
+// Freeverb tunings, in samples at 44.1 kHz.
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+const STEREO_SPREAD: usize = 23;
+const INPUT_GAIN: f32 = 0.015;
+
+struct Comb {
+    buf: Vec<f32>,
+    pos: usize,
+    store: f32,
+}
+
+impl Comb {
+    fn new(len: usize) -> Comb {
+        Comb {
+            buf: vec![0.0; len.max(1)],
+            pos: 0,
+            store: 0.0,
+        }
+    }
+
+    fn process(&mut self, input: f32, feedback: f32, damp: f32) -> f32 {
+        let out = self.buf[self.pos];
+        self.store = out + damp * (self.store - out);
+        self.buf[self.pos] = input + self.store * feedback;
+        self.pos = (self.pos + 1) % self.buf.len();
+        out
+    }
+}
+
+struct Allpass {
+    buf: Vec<f32>,
+    pos: usize,
+}
+
+impl Allpass {
+    fn new(len: usize) -> Allpass {
+        Allpass {
+            buf: vec![0.0; len.max(1)],
+            pos: 0,
+        }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buf[self.pos];
+        self.buf[self.pos] = input + delayed * 0.5;
+        self.pos = (self.pos + 1) % self.buf.len();
+        delayed - input
+    }
+}
+
+/// An algorithmic reverb in the style of Freeverb.
+///
+/// Buffer inputs are the left and right send, outputs the left and right
+/// return. Control inputs are room size (0..1), damping (0..1) and the
+/// linear return level.
+pub struct Reverb {
+    combs: [Vec<Comb>; 2],
+    allpasses: [Vec<Allpass>; 2],
+}
+
+impl Reverb {
+    pub fn new(sample_rate: f32) -> Reverb {
+        let scale = |len: usize| (len as f32 * sample_rate / 44_100.0) as usize;
+        let combs = |spread: usize| COMB_TUNING.iter().map(|&t| Comb::new(scale(t + spread))).collect();
+        let allpasses =
+            |spread: usize| ALLPASS_TUNING.iter().map(|&t| Allpass::new(scale(t + spread))).collect();
+        Reverb {
+            combs: [combs(0), combs(STEREO_SPREAD)],
+            allpasses: [allpasses(0), allpasses(STEREO_SPREAD)],
+        }
+    }
+}
+
+impl Module for Reverb {
+    fn n_bufs_out(&self) -> usize {
+        2
+    }
+
+    fn to_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn process(
+        &mut self,
+        control_in: &[f32],
+        _control_out: &mut [f32],
+        buf_in: &[&Buffer],
+        buf_out: &mut [Buffer],
+    ) {
+        let feedback = 0.7 + 0.28 * control_in[0].max(0.0).min(1.0);
+        let damp = 0.4 * control_in[1].max(0.0).min(1.0);
+        let level = control_in[2].max(0.0);
+
+        let in_l = buf_in[0].get();
+        let in_r = buf_in[1].get();
+        for (side, out) in buf_out.iter_mut().take(2).enumerate() {
+            let out = out.get_mut();
+            for i in 0..N_SAMPLES_PER_CHUNK {
+                let input = (in_l[i] + in_r[i]) * INPUT_GAIN;
+                let mut acc = 0.0;
+                for comb in self.combs[side].iter_mut() {
+                    acc += comb.process(input, feedback, damp);
+                }
+                for allpass in self.allpasses[side].iter_mut() {
+                    acc = allpass.process(acc);
+                }
+                out[i] = acc * level;
+            }
+        }
+    }
+}
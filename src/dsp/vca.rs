@@ -0,0 +1,46 @@
+//! A linear gain stage.
+
+use std::any::Any;
+
+use synthesizer_io_core::module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+
+/// Multiplies its input buffer by a linear gain control, ramping across the
+/// chunk so gain changes don't click.
+pub struct Vca {
+    last_gain: f32,
+}
+
+impl Vca {
+    pub fn new() -> Vca {
+        Vca { last_gain: 0.0 }
+    }
+}
+
+impl Module for Vca {
+    fn n_bufs_out(&self) -> usize {
+        1
+    }
+
+    fn to_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn process(
+        &mut self,
+        control_in: &[f32],
+        _control_out: &mut [f32],
+        buf_in: &[&Buffer],
+        buf_out: &mut [Buffer],
+    ) {
+        let gain = control_in[0];
+        let step = (gain - self.last_gain) * (1.0 / N_SAMPLES_PER_CHUNK as f32);
+        let input = buf_in[0].get();
+        let out = buf_out[0].get_mut();
+        let mut g = self.last_gain;
+        for i in 0..N_SAMPLES_PER_CHUNK {
+            g += step;
+            out[i] = input[i] * g;
+        }
+        self.last_gain = gain;
+    }
+}
@@ -0,0 +1,64 @@
+//! Per-voice velocity response.
+
+use std::any::Any;
+
+use synthesizer_io_core::module::{Buffer, Module};
+
+/// Turns the velocity of the last note-on into an amplitude and a cutoff
+/// control for its voice.
+///
+/// Control inputs are the channel cutoff (log2 Hz), the amplitude amount
+/// (0..1), the cutoff amount (octaves) and the curve (-1..1, 0 is linear,
+/// positive values need harder playing). Control outputs are the voice
+/// cutoff (log2 Hz) and the linear amplitude gain.
+///
+/// A full velocity note leaves both the cutoff and the amplitude untouched,
+/// softer notes are attenuated and darkened by the respective amounts.
+pub struct Velocity {
+    velocity: f32,
+}
+
+impl Velocity {
+    pub fn new() -> Velocity {
+        Velocity { velocity: 1.0 }
+    }
+
+    /// Shape a normalized velocity with the given curve.
+    pub fn shape(velocity: f32, curve: f32) -> f32 {
+        let exponent = (curve.max(-1.0).min(1.0) * 2.0).exp2();
+        velocity.max(0.0).min(1.0).powf(exponent)
+    }
+}
+
+impl Module for Velocity {
+    fn n_ctrl_out(&self) -> usize {
+        2
+    }
+
+    fn to_any(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn handle_note(&mut self, _midi_num: f32, velocity: f32, on: bool) {
+        // Keep the velocity of the note through its release.
+        if on {
+            self.velocity = velocity * (1.0 / 127.0);
+        }
+    }
+
+    fn process(
+        &mut self,
+        control_in: &[f32],
+        control_out: &mut [f32],
+        _buf_in: &[&Buffer],
+        _buf_out: &mut [Buffer],
+    ) {
+        let cutoff = control_in[0];
+        let amp_amount = control_in[1].max(0.0).min(1.0);
+        let cutoff_amount = control_in[2];
+        let shaped = Velocity::shape(self.velocity, control_in[3]);
+
+        control_out[0] = (cutoff + cutoff_amount * (shaped - 1.0)).max(20f32.log2());
+        control_out[1] = 1.0 - amp_amount * (1.0 - shaped);
+    }
+}
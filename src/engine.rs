@@ -16,8 +16,15 @@
 
 //! Interface for the audio engine.
 
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
 use time;
-use crate::config;
+use crate::clock::Clock;
+use crate::config::SynthMode;
+use crate::dsp;
 
 use synthesizer_io_core::graph::{IntoBoxedSlice, Message, Node, Note, SetParam};
 use synthesizer_io_core::id_allocator::IdAllocator;
@@ -34,7 +41,17 @@ pub struct Engine {
     core: Core,
     current_channel : usize,
     max_channels : usize,
-    control_maps: [Option<ControlMap>; config::CHANNEL_COUNT],
+    // step of the selected channel's sequencer being edited
+    edit_step: usize,
+    // sound controls are locked on the edit step instead of changed
+    param_lock: bool,
+    control_maps: Vec<ControlMap>,
+    master_map: Option<MasterMap>,
+    fx_map: Option<FxMap>,
+    bpm: f32,
+    // delay time in beats when the delay follows the tempo
+    delay_sync: Option<f32>,
+    clock: Arc<Clock>,
 }
 
 /// Type used to identify nodes in the external interface (not to be confused
@@ -53,6 +70,10 @@ struct Core {
     sample_rate: f32,
     rx: Receiver<Message>,
     tx: Sender<Message>,
+    // messages sent to the worker that it hasn't handed back yet
+    in_flight: Cell<u64>,
+    // last value set on each control node, to restore parameter locks
+    ctrl_values: HashMap<usize, f32>,
 
     id_alloc: IdAllocator,
 
@@ -64,19 +85,223 @@ struct Core {
 pub struct ControlMap {
     pub cutoff: usize,
     pub reso: usize,
+    pub filter_mode: usize,
+    pub drive: usize,
 
     pub attack: usize,
     pub decay: usize,
     pub sustain: usize,
     pub release: usize,
 
+    // mixer strip: linear gain, pan (-1..1), mute and solo (0 or 1)
+    pub volume: usize,
+    pub pan: usize,
+    pub mute: usize,
+    pub solo: usize,
+    pub delay_send: usize,
+    pub reverb_send: usize,
+
+    // velocity response: amplitude amount, cutoff amount (octaves) and curve
+    pub vel_amp: usize,
+    pub vel_cutoff: usize,
+    pub vel_curve: usize,
+
+    // performance: bend (-1..1) and its range in semitones, for the channel
+    // and for single voices, mod wheel and pressure (0..1), and how many
+    // octaves pressure and slide open the filter
+    pub bend: usize,
+    pub bend_range: usize,
+    pub voice_bend_range: usize,
+    pub mod_wheel: usize,
+    pub pressure: usize,
+    pub expression_cutoff: usize,
+
     // node number of node that can be replaced to inject more audio
     pub ext: usize,
 
-    pub note_receivers: [Vec<usize>; config::VOICE_COUNT],
+    pub note_receivers: Vec<Vec<usize>>,
+    // pitch node of each voice, notes sent only there change the pitch
+    // without retriggering the voice
+    pub pitch_receivers: Vec<usize>,
+    // portamento time in seconds
+    pub glide: usize,
+    // envelope of each voice, replaced to cut the voice off
+    pub envelopes: Vec<usize>,
+    // per voice detune in semitones and pan (-1..1), set for unison
+    pub voice_detune: Vec<usize>,
+    pub voice_pan: Vec<usize>,
+    // per voice bend (-1..1), pressure and slide (0..1), for poly
+    // aftertouch and MPE
+    pub voice_bend: Vec<usize>,
+    pub voice_pressure: Vec<usize>,
+    pub voice_slide: Vec<usize>,
+}
+/// Per voice performance controls.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VoiceExpression {
+    Bend,
+    Pressure,
+    Slide,
+}
+
+/// The controls of a channel, by name, for sequencer parameter locks.
+/// Values are in the units of the control, e.g. log2 Hz for the cutoff.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Param {
+    Cutoff,
+    Reso,
+    FilterMode,
+    Drive,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Volume,
+    Pan,
+    Mute,
+    Solo,
+    DelaySend,
+    ReverbSend,
+    VelAmp,
+    VelCutoff,
+    VelCurve,
+    Bend,
+    BendRange,
+    VoiceBendRange,
+    ModWheel,
+    Pressure,
+    ExpressionCutoff,
+    Glide,
 }
-const NONE_VEC_USIZE: Vec<usize> = vec![];
-const NONE_CONTROL_MAP: Option<ControlMap> = None;
+
+impl Param {
+    pub const ALL: [Param; 24] = [
+        Param::Cutoff,
+        Param::Reso,
+        Param::FilterMode,
+        Param::Drive,
+        Param::Attack,
+        Param::Decay,
+        Param::Sustain,
+        Param::Release,
+        Param::Volume,
+        Param::Pan,
+        Param::Mute,
+        Param::Solo,
+        Param::DelaySend,
+        Param::ReverbSend,
+        Param::VelAmp,
+        Param::VelCutoff,
+        Param::VelCurve,
+        Param::Bend,
+        Param::BendRange,
+        Param::VoiceBendRange,
+        Param::ModWheel,
+        Param::Pressure,
+        Param::ExpressionCutoff,
+        Param::Glide,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Param::Cutoff => "cutoff",
+            Param::Reso => "reso",
+            Param::FilterMode => "filter_mode",
+            Param::Drive => "drive",
+            Param::Attack => "attack",
+            Param::Decay => "decay",
+            Param::Sustain => "sustain",
+            Param::Release => "release",
+            Param::Volume => "volume",
+            Param::Pan => "pan",
+            Param::Mute => "mute",
+            Param::Solo => "solo",
+            Param::DelaySend => "delay_send",
+            Param::ReverbSend => "reverb_send",
+            Param::VelAmp => "vel_amp",
+            Param::VelCutoff => "vel_cutoff",
+            Param::VelCurve => "vel_curve",
+            Param::Bend => "bend",
+            Param::BendRange => "bend_range",
+            Param::VoiceBendRange => "voice_bend_range",
+            Param::ModWheel => "mod_wheel",
+            Param::Pressure => "pressure",
+            Param::ExpressionCutoff => "expression_cutoff",
+            Param::Glide => "glide",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Param> {
+        Param::ALL.iter().cloned().find(|param| param.name() == name)
+    }
+}
+
+impl ControlMap {
+    /// The control node of a parameter.
+    pub fn param(&self, param: Param) -> usize {
+        match param {
+            Param::Cutoff => self.cutoff,
+            Param::Reso => self.reso,
+            Param::FilterMode => self.filter_mode,
+            Param::Drive => self.drive,
+            Param::Attack => self.attack,
+            Param::Decay => self.decay,
+            Param::Sustain => self.sustain,
+            Param::Release => self.release,
+            Param::Volume => self.volume,
+            Param::Pan => self.pan,
+            Param::Mute => self.mute,
+            Param::Solo => self.solo,
+            Param::DelaySend => self.delay_send,
+            Param::ReverbSend => self.reverb_send,
+            Param::VelAmp => self.vel_amp,
+            Param::VelCutoff => self.vel_cutoff,
+            Param::VelCurve => self.vel_curve,
+            Param::Bend => self.bend,
+            Param::BendRange => self.bend_range,
+            Param::VoiceBendRange => self.voice_bend_range,
+            Param::ModWheel => self.mod_wheel,
+            Param::Pressure => self.pressure,
+            Param::ExpressionCutoff => self.expression_cutoff,
+            Param::Glide => self.glide,
+        }
+    }
+}
+
+/// Control nodes of the master bus.
+#[derive(Clone)]
+pub struct MasterMap {
+    // linear gain applied in the mixer
+    pub gain: usize,
+    // limiter ceiling in dBFS and release time in seconds
+    pub ceiling: usize,
+    pub release: usize,
+
+    pub meter: Arc<dsp::Meter>,
+}
+
+/// Control nodes of the send effects shared by all channels.
+#[derive(Clone)]
+pub struct FxMap {
+    // delay time in seconds, feedback (0..1), feedback low-pass cutoff
+    // (log2 Hz), ping-pong (0 or 1) and return level
+    pub delay_time: usize,
+    pub delay_feedback: usize,
+    pub delay_damping: usize,
+    pub delay_ping_pong: usize,
+    pub delay_return: usize,
+
+    // room size (0..1), damping (0..1) and return level
+    pub reverb_size: usize,
+    pub reverb_damping: usize,
+    pub reverb_return: usize,
+
+    // the effect nodes and the mixer feeding them, to reset their tails
+    pub delay: usize,
+    pub reverb: usize,
+    pub sends: usize,
+}
+
 
 struct MonitorQueues {
     rx: Receiver<Vec<f32>>,
@@ -90,43 +315,96 @@ impl Engine {
     pub fn new(sample_rate: f32, rx: Receiver<Message>, tx: Sender<Message>) -> Engine {
         let core = Core::new(sample_rate, rx, tx);
         Engine {
+            clock: Arc::new(Clock::new(sample_rate)),
             core: core,
             current_channel: 0,
             max_channels : 1,
-            control_maps: [NONE_CONTROL_MAP; config::CHANNEL_COUNT],
+            edit_step: 0,
+            param_lock: false,
+            control_maps: vec![],
+            master_map: None,
+            fx_map: None,
+            bpm: 120.0,
+            delay_sync: None,
         }
     }
 
-    /// Initialize the engine with a simple mono synth.
-    pub fn init_monosynth(&mut self) {
-        self.max_channels = config::CHANNEL_COUNT;
-        for c in 0..config::CHANNEL_COUNT{
-            let mut control_map = self.core.init_controls();
-            let (control_map, _) = self.core.init_monosynth(0, control_map);
-            self.control_maps[c] = Some(control_map);
+    /// Initialize the engine with the configured voice architecture. Both
+    /// build the same graph, mono playing is done by the `NoteModule` on
+    /// the voice pool, so channels can switch at any time.
+    pub fn init_synth(&mut self, synth: SynthMode, channel_count: usize, voice_count: usize) {
+        match synth {
+            SynthMode::Poly | SynthMode::Mono => self.init_polysynth(channel_count, voice_count),
         }
     }
-    /// Initialize the engine with a simple mono synth.
-    pub fn init_polysynth(&mut self) {
-        
-        let mut ch_outputs: [usize;config::CHANNEL_COUNT] = [0;config::CHANNEL_COUNT];
-        self.max_channels = config::CHANNEL_COUNT;
-        for c in 0..config::CHANNEL_COUNT {
-            let mut voice_outputs: [usize;config::VOICE_COUNT] = [0;config::VOICE_COUNT];
-            let mut control_map = self.core.init_controls();
 
-            for v in 0..config::VOICE_COUNT {
+    /// Initialize the engine with a polyphonic synth per channel, mixed down
+    /// to stereo.
+    pub fn init_polysynth(&mut self, channel_count: usize, voice_count: usize) {
+        
+        let mut ch_outputs: Vec<usize> = Vec::with_capacity(channel_count);
+        self.max_channels = channel_count;
+        self.control_maps.clear();
+        for _ in 0..channel_count {
+            let mut voice_outputs: Vec<usize> = Vec::with_capacity(voice_count);
+            let mut control_map = self.core.init_controls(voice_count);
+
+            for v in 0..voice_count {
                 let (c, o) = self.core.init_monosynth(v, control_map);
                 control_map = c;
-                voice_outputs[v] = o;
+                voice_outputs.push(o);
             }
-            let id = self.core.id_alloc.alloc();
-            self.core.update_sum_node(id, &voice_outputs);
+            let buf_wiring: Vec<_> = voice_outputs.iter().map(|&o| (o, 0)).collect();
+            let ctrl_wiring: Vec<_> = control_map.voice_pan.iter().map(|&p| (p, 0)).collect();
+            let id = self.core.create_node(dsp::VoiceMix::new(voice_count), buf_wiring, ctrl_wiring);
 
-            ch_outputs[c] = id;
-            self.control_maps[c] = Some(control_map);
+            ch_outputs.push(id);
+            self.control_maps.push(control_map);
         }
-        self.core.update_sum_node(0, &ch_outputs);
+        let master_gain = self.core.create_node(modules::SmoothCtrl::new(0.5), [], []);
+        let ceiling = self.core.create_node(modules::SmoothCtrl::new(-0.3), [], []);
+        let release = self.core.create_node(modules::SmoothCtrl::new(0.1), [], []);
+        let mut ctrl_wiring = vec![];
+        for control_map in self.control_maps.iter() {
+            ctrl_wiring.push((control_map.volume, 0));
+            ctrl_wiring.push((control_map.pan, 0));
+            ctrl_wiring.push((control_map.mute, 0));
+            ctrl_wiring.push((control_map.solo, 0));
+            ctrl_wiring.push((control_map.delay_send, 0));
+            ctrl_wiring.push((control_map.reverb_send, 0));
+        }
+        ctrl_wiring.push((master_gain, 0));
+        let mixer = self.core.id_alloc.alloc();
+        self.core.update_mixer_node(mixer, &ch_outputs, ctrl_wiring);
+
+        let (fx_map, fx_outputs) = self.core.init_fx(mixer);
+        let mut outputs = vec![(mixer, 0), (mixer, 1)];
+        outputs.extend_from_slice(&fx_outputs);
+        let sum = self.core.create_node(dsp::StereoSum::new(), outputs, []);
+
+        let meter = Arc::new(dsp::Meter::new());
+        let module = Box::new(dsp::Limiter::new(self.core.sample_rate, meter.clone()));
+        let wiring = [(ceiling, 0), (release, 0)];
+        self.core.send_node(Node::create(module, 0, [(sum, 0), (sum, 1)], wiring));
+
+        self.master_map = Some(MasterMap {
+            gain: master_gain,
+            ceiling,
+            release,
+            meter,
+        });
+        self.fx_map = Some(fx_map);
+    }
+
+    /// The clock mapping host time to worker timestamps. It is driven by
+    /// the audio output.
+    pub fn get_clock(&self) -> Arc<Clock> {
+        self.clock.clone()
+    }
+
+    /// Timestamp for a message that should take effect now.
+    pub fn now(&self) -> u64 {
+        self.clock.now()
     }
 
     pub fn send(&self, msg: Message) {
@@ -139,6 +417,13 @@ impl Engine {
         self.core.poll_rx()
     }
 
+    /// Number of messages the worker hasn't processed yet. Polls the return
+    /// queue first, since the worker hands every message back once done.
+    pub fn backlog(&mut self) -> u64 {
+        self.core.poll_rx();
+        self.core.in_flight()
+    }
+
     /// Poll the monitor queue, retrieving audio data.
     pub fn poll_monitor(&mut self) -> Vec<f32> {
         self.core.poll_monitor()
@@ -160,12 +445,12 @@ impl Engine {
     }
 
     pub fn get_current_control_map(&self) -> ControlMap {
-        let control_map = self.control_maps[self.current_channel].as_ref().unwrap().clone();
+        let control_map = self.control_maps[self.current_channel].clone();
         control_map
     }
 
     pub fn get_control_map(&self, channel: usize) -> ControlMap {
-        let control_map = self.control_maps[channel].as_ref().unwrap().clone();
+        let control_map = self.control_maps[channel].clone();
         control_map
     }
 
@@ -178,10 +463,206 @@ impl Engine {
             self.current_channel = channel;
         }
     }
+
+    pub fn get_edit_step(&self) -> usize {
+        self.edit_step
+    }
+
+    pub fn set_edit_step(&mut self, step: usize) {
+        self.edit_step = step;
+    }
+
+    pub fn get_param_lock(&self) -> bool {
+        self.param_lock
+    }
+
+    pub fn set_param_lock(&mut self, param_lock: bool) {
+        self.param_lock = param_lock;
+    }
+
+    pub fn set_filter_mode(&mut self, channel: usize, mode: dsp::FilterMode, ts: u64) {
+        let filter_mode = self.get_control_map(channel).filter_mode;
+        self.set_ctrl(filter_mode, mode.to_ctrl(), ts);
+    }
+
+    pub fn set_mute(&mut self, channel: usize, mute: bool, ts: u64) {
+        let mute_ix = self.get_control_map(channel).mute;
+        self.set_ctrl(mute_ix, if mute { 1.0 } else { 0.0 }, ts);
+    }
+
+    pub fn set_solo(&mut self, channel: usize, solo: bool, ts: u64) {
+        let solo_ix = self.get_control_map(channel).solo;
+        self.set_ctrl(solo_ix, if solo { 1.0 } else { 0.0 }, ts);
+    }
+
+    /// Set the portamento time of a channel in seconds, 0 for none.
+    pub fn set_glide(&mut self, channel: usize, seconds: f32, ts: u64) {
+        let glide = self.get_control_map(channel).glide;
+        self.set_ctrl(glide, seconds.max(0.0), ts);
+    }
+
+    /// Set the detune (semitones) and pan (-1..1) of one voice, for
+    /// stacking voices in unison. Takes effect from the voice's next note.
+    pub fn set_voice_spread(&mut self, channel: usize, voice: usize, detune: f32, pan: f32, ts: u64) {
+        let control_map = self.get_control_map(channel);
+        self.set_ctrl(control_map.voice_detune[voice], detune, ts);
+        self.set_ctrl(control_map.voice_pan[voice], pan.max(-1.0).min(1.0), ts);
+    }
+
+    /// Set the pitch bend of a channel, -1..1 of its bend range.
+    pub fn set_bend(&mut self, channel: usize, bend: f32, ts: u64) {
+        let bend_ctrl = self.get_control_map(channel).bend;
+        self.set_ctrl(bend_ctrl, bend.max(-1.0).min(1.0), ts);
+    }
+
+    /// Set the bend ranges of a channel, in semitones, for the whole channel
+    /// and for single voices.
+    pub fn set_bend_range(&mut self, channel: usize, semitones: f32, voice_semitones: f32, ts: u64) {
+        let control_map = self.get_control_map(channel);
+        self.set_ctrl(control_map.bend_range, semitones, ts);
+        self.set_ctrl(control_map.voice_bend_range, voice_semitones, ts);
+    }
+
+    pub fn set_mod_wheel(&mut self, channel: usize, value: f32, ts: u64) {
+        let mod_wheel = self.get_control_map(channel).mod_wheel;
+        self.set_ctrl(mod_wheel, value.max(0.0).min(1.0), ts);
+    }
+
+    /// Set the channel pressure (aftertouch), 0..1.
+    pub fn set_pressure(&mut self, channel: usize, value: f32, ts: u64) {
+        let pressure = self.get_control_map(channel).pressure;
+        self.set_ctrl(pressure, value.max(0.0).min(1.0), ts);
+    }
+
+    /// Set the bend (-1..1), pressure or slide (0..1) of single voices.
+    pub fn set_voice_expression(&mut self, channel: usize, voices: Range<usize>, kind: VoiceExpression, value: f32, ts: u64) {
+        let control_map = self.get_control_map(channel);
+        let (ctrls, value) = match kind {
+            VoiceExpression::Bend => (&control_map.voice_bend, value.max(-1.0).min(1.0)),
+            VoiceExpression::Pressure => (&control_map.voice_pressure, value.max(0.0).min(1.0)),
+            VoiceExpression::Slide => (&control_map.voice_slide, value.max(0.0).min(1.0)),
+        };
+        for v in voices {
+            self.set_ctrl(ctrls[v], value, ts);
+        }
+    }
+
+    /// Set the linear gain applied after all channels are mixed.
+    pub fn set_master_gain(&mut self, gain: f32, ts: u64) {
+        if let Some(master_gain) = self.master_map.as_ref().map(|m| m.gain) {
+            self.set_ctrl(master_gain, gain.max(0.0), ts);
+        }
+    }
+
+    pub fn get_master_map(&self) -> Option<MasterMap> {
+        self.master_map.clone()
+    }
+
+    /// Cut off every voice of a channel at once, by replacing their
+    /// envelopes with idle ones. Notes should be released first.
+    pub fn kill_voices(&mut self, channel: usize) {
+        let control_map = self.get_control_map(channel);
+        for &adsr in control_map.envelopes.iter() {
+            self.core.update_adsr_node(adsr, &control_map);
+        }
+    }
+
+    /// Clear the delay and reverb tails.
+    pub fn clear_fx(&mut self) {
+        if let Some(fx_map) = self.fx_map.clone() {
+            self.core.update_fx_nodes(&fx_map);
+        }
+    }
+
+    /// Output levels measured by the limiter, shared with the audio thread.
+    pub fn meter(&self) -> Option<Arc<dsp::Meter>> {
+        self.master_map.as_ref().map(|m| m.meter.clone())
+    }
+
+    pub fn get_fx_map(&self) -> Option<FxMap> {
+        self.fx_map.clone()
+    }
+
+    pub fn get_tempo(&self) -> f32 {
+        self.bpm
+    }
+
+    /// Set the tempo used by tempo synced effects.
+    pub fn set_tempo(&mut self, bpm: f32, ts: u64) {
+        self.bpm = bpm;
+        if let Some(beats) = self.delay_sync {
+            self.send_delay_time(beats * 60.0 / bpm, ts);
+        }
+    }
+
+    /// Set a free running delay time in seconds.
+    pub fn set_delay_time(&mut self, seconds: f32, ts: u64) {
+        self.delay_sync = None;
+        self.send_delay_time(seconds, ts);
+    }
+
+    /// Lock the delay time to a number of beats at the current tempo.
+    pub fn set_delay_sync(&mut self, beats: f32, ts: u64) {
+        self.delay_sync = Some(beats);
+        self.send_delay_time(beats * 60.0 / self.bpm, ts);
+    }
+
+    fn send_delay_time(&mut self, seconds: f32, ts: u64) {
+        if let Some(delay_time) = self.fx_map.as_ref().map(|fx| fx.delay_time) {
+            let seconds = seconds.max(0.0).min(dsp::MAX_DELAY_SECONDS);
+            self.set_ctrl(delay_time, seconds, ts);
+        }
+    }
+
+    pub fn set_delay_ping_pong(&mut self, ping_pong: bool, ts: u64) {
+        if let Some(delay_ping_pong) = self.fx_map.as_ref().map(|fx| fx.delay_ping_pong) {
+            self.set_ctrl(delay_ping_pong, if ping_pong { 1.0 } else { 0.0 }, ts);
+        }
+    }
+
+    /// Send a raw value to the first parameter of a control node.
+    pub fn set_ctrl(&mut self, ix: usize, val: f32, ts: u64) {
+        self.core.ctrl_values.insert(ix, val);
+        let param = SetParam {
+            ix: ix,
+            param_ix: 0,
+            val: val,
+            timestamp: ts,
+        };
+        self.send(Message::SetParam(param));
+    }
+
+    /// Override a parameter of a channel for a sequencer step, leaving the
+    /// value it goes back to alone.
+    pub fn lock_param(&mut self, channel: usize, param: Param, value: f32, ts: u64) {
+        let param = SetParam {
+            ix: self.control_maps[channel].param(param),
+            param_ix: 0,
+            val: value,
+            timestamp: ts,
+        };
+        self.send(Message::SetParam(param));
+    }
+
+    /// Put a locked parameter back to the value last set.
+    pub fn restore_param(&mut self, channel: usize, param: Param, ts: u64) {
+        let ix = self.control_maps[channel].param(param);
+        if let Some(&val) = self.core.ctrl_values.get(&ix) {
+            let param = SetParam {
+                ix,
+                param_ix: 0,
+                val,
+                timestamp: ts,
+            };
+            self.send(Message::SetParam(param));
+        }
+    }
+
     pub fn set_ctrl_const(&mut self, value: f32, lo: f32, hi: f32, ix: usize,
         ts: u64)
     {
         let value = lo + value * (hi - lo);
+        self.core.ctrl_values.insert(ix, value);
         let param = SetParam {
             ix: ix,
             param_ix: 0,
@@ -202,6 +683,8 @@ impl Core {
             sample_rate,
             rx,
             tx,
+            in_flight: Cell::new(0),
+            ctrl_values: HashMap::new(),
             id_alloc,
             monitor_queues,
         }
@@ -226,23 +709,80 @@ impl Core {
         ));
         id
     }
-    fn init_controls(&mut self) -> ControlMap {
-        let attack = self.create_node(modules::SmoothCtrl::new(5.0), [], []);
-        let decay = self.create_node(modules::SmoothCtrl::new(5.0), [], []);
-        let sustain = self.create_node(modules::SmoothCtrl::new(4.0), [], []);
-        let release = self.create_node(modules::SmoothCtrl::new(5.0), [], []);
+    /// Create a smoothed control node, remembering its value.
+    fn smooth_ctrl(&mut self, value: f32) -> usize {
+        let id = self.create_node(modules::SmoothCtrl::new(value), [], []);
+        self.ctrl_values.insert(id, value);
+        id
+    }
+
+    /// Create a control node, remembering its value.
+    fn const_ctrl(&mut self, value: f32) -> usize {
+        let id = self.create_node(modules::ConstCtrl::new(value), [], []);
+        self.ctrl_values.insert(id, value);
+        id
+    }
+
+    fn init_controls(&mut self, voice_count: usize) -> ControlMap {
+        let attack = self.smooth_ctrl(5.0);
+        let decay = self.smooth_ctrl(5.0);
+        let sustain = self.smooth_ctrl(4.0);
+        let release = self.smooth_ctrl(5.0);
         let ext = self.create_node(modules::Sum::new(), [], []);
-        let cutoff = self.create_node(modules::SmoothCtrl::new(880.0f32.log2()), [], []);
-        let reso = self.create_node(modules::SmoothCtrl::new(0.5), [], []);
+        let volume = self.smooth_ctrl(1.0);
+        let pan = self.smooth_ctrl(0.0);
+        let mute = self.const_ctrl(0.0);
+        let solo = self.const_ctrl(0.0);
+        let delay_send = self.smooth_ctrl(0.0);
+        let reverb_send = self.smooth_ctrl(0.0);
+        let cutoff = self.smooth_ctrl(880.0f32.log2());
+        let reso = self.smooth_ctrl(0.5);
+        let filter_mode = self.const_ctrl(dsp::FilterMode::LowPass.to_ctrl());
+        let drive = self.smooth_ctrl(0.0);
+        let vel_amp = self.smooth_ctrl(0.7);
+        let vel_cutoff = self.smooth_ctrl(1.0);
+        let vel_curve = self.smooth_ctrl(0.0);
+        let glide = self.const_ctrl(0.0);
+        let bend = self.smooth_ctrl(0.0);
+        let bend_range = self.const_ctrl(2.0);
+        let voice_bend_range = self.const_ctrl(48.0);
+        let mod_wheel = self.smooth_ctrl(0.0);
+        let pressure = self.smooth_ctrl(0.0);
+        let expression_cutoff = self.smooth_ctrl(2.0);
         ControlMap {
             cutoff,
             reso,
+            filter_mode,
+            drive,
             attack,
             decay,
             sustain,
             release,
+            volume,
+            pan,
+            mute,
+            solo,
+            delay_send,
+            reverb_send,
+            vel_amp,
+            vel_cutoff,
+            vel_curve,
+            bend,
+            bend_range,
+            voice_bend_range,
+            mod_wheel,
+            pressure,
+            expression_cutoff,
             ext,
-            note_receivers: [NONE_VEC_USIZE; config::VOICE_COUNT],
+            note_receivers: vec![vec![]; voice_count],
+            pitch_receivers: vec![0; voice_count],
+            glide,
+            envelopes: vec![0; voice_count],
+            voice_detune: vec![0; voice_count],
+            voice_pan: vec![0; voice_count],
+            voice_bend: vec![0; voice_count],
+            voice_pressure: vec![0; voice_count],
+            voice_slide: vec![0; voice_count],
         }
     }
 
@@ -252,26 +792,59 @@ impl Core {
         mut control_map: ControlMap,
     ) -> (ControlMap, usize) {
         let sample_rate = self.sample_rate;
-        let note_pitch = self.create_node(modules::NotePitch::new(), [], []);
+        let detune = self.create_node(modules::ConstCtrl::new(0.0), [], []);
+        let pan = self.create_node(modules::ConstCtrl::new(0.0), [], []);
+        let bend = self.create_node(modules::ConstCtrl::new(0.0), [], []);
+        let pressure = self.create_node(modules::ConstCtrl::new(0.0), [], []);
+        let slide = self.create_node(modules::ConstCtrl::new(0.0), [], []);
+        let velocity = self.create_node(
+            dsp::Velocity::new(),
+            [],
+            vec![
+                (control_map.cutoff, 0),
+                (control_map.vel_amp, 0),
+                (control_map.vel_cutoff, 0),
+                (control_map.vel_curve, 0),
+            ],
+        );
+        let expression = self.create_node(
+            dsp::Expression::new(sample_rate),
+            [],
+            vec![
+                (velocity, 0),
+                (control_map.bend, 0),
+                (control_map.bend_range, 0),
+                (bend, 0),
+                (control_map.voice_bend_range, 0),
+                (control_map.mod_wheel, 0),
+                (control_map.pressure, 0),
+                (pressure, 0),
+                (slide, 0),
+                (control_map.expression_cutoff, 0),
+            ],
+        );
+        let note_pitch = self.create_node(
+            dsp::Glide::new(sample_rate),
+            [],
+            [(control_map.glide, 0), (detune, 0), (expression, 0)],
+        );
         let saw = self.create_node(modules::Saw::new(sample_rate), [], [(note_pitch, 0)]);
 
         let filter_out = self.create_node(
-            modules::Biquad::new(sample_rate),
+            dsp::Filter::new(sample_rate),
             [(saw, 0)],
-            [(control_map.cutoff, 0), (control_map.reso, 0)],
-        );
-        let adsr = self.create_node(
-            modules::Adsr::new(),
-            [],
             vec![
-                (control_map.attack, 0),
-                (control_map.decay, 0),
-                (control_map.sustain, 0),
-                (control_map.release, 0),
+                (expression, 1),
+                (control_map.reso, 0),
+                (control_map.filter_mode, 0),
+                (control_map.drive, 0),
             ],
         );
+        let adsr = self.id_alloc.alloc();
+        self.update_adsr_node(adsr, &control_map);
 
         let env_out = self.create_node(modules::Gain::new(), [(filter_out, 0)], [(adsr, 0)]);
+        let vca_out = self.create_node(dsp::Vca::new(), [(env_out, 0)], [(velocity, 1)]);
 
         let ext_gain = self.create_node(modules::ConstCtrl::new(-2.0), [], []);
         let ext_atten = self.create_node(
@@ -280,7 +853,7 @@ impl Core {
             [(ext_gain, 0)],
         );
 
-        let monitor_in = self.create_node(modules::Sum::new(), [(env_out, 0), (ext_atten, 0)], []);
+        let monitor_in = self.create_node(modules::Sum::new(), [(vca_out, 0), (ext_atten, 0)], []);
 
         let (monitor, tx, rx) = modules::Monitor::new();
         self.monitor_queues = Some(MonitorQueues { tx, rx });
@@ -288,12 +861,31 @@ impl Core {
 
         control_map.note_receivers[voice_number].push(note_pitch);
         control_map.note_receivers[voice_number].push(adsr);
+        control_map.note_receivers[voice_number].push(velocity);
+        control_map.envelopes[voice_number] = adsr;
+        control_map.pitch_receivers[voice_number] = note_pitch;
+        control_map.voice_detune[voice_number] = detune;
+        control_map.voice_pan[voice_number] = pan;
+        control_map.voice_bend[voice_number] = bend;
+        control_map.voice_pressure[voice_number] = pressure;
+        control_map.voice_slide[voice_number] = slide;
 
         (control_map, monitor)
     }
 
+    fn update_adsr_node(&mut self, adsr: usize, control_map: &ControlMap) {
+        let wiring = vec![
+            (control_map.attack, 0),
+            (control_map.decay, 0),
+            (control_map.sustain, 0),
+            (control_map.release, 0),
+        ];
+        self.send_node(Node::create(Box::new(modules::Adsr::new()), adsr, [], wiring));
+    }
+
     fn send(&self, msg: Message) {
         self.tx.send(msg);
+        self.in_flight.set(self.in_flight.get() + 1);
     }
 
     fn send_node(&mut self, node: Node) {
@@ -301,7 +893,13 @@ impl Core {
     }
 
     fn poll_rx(&mut self) -> usize {
-        self.rx.recv().count()
+        let n = self.rx.recv().count();
+        self.in_flight.set(self.in_flight.get().saturating_sub(n as u64));
+        n
+    }
+
+    fn in_flight(&self) -> u64 {
+        self.in_flight.get()
     }
 
     fn poll_monitor(&self) -> Vec<f32> {
@@ -322,6 +920,72 @@ impl Core {
         self.send_node(Node::create(module, sum_node, buf_wiring, []));
     }
 
+    /// Create the send effects fed by the mixer, returning their controls and
+    /// their stereo return outputs.
+    fn init_fx(&mut self, mixer: usize) -> (FxMap, Vec<(usize, usize)>) {
+        let delay_time = self.create_node(modules::SmoothCtrl::new(0.375), [], []);
+        let delay_feedback = self.create_node(modules::SmoothCtrl::new(0.4), [], []);
+        let delay_damping = self.create_node(modules::SmoothCtrl::new(4000f32.log2()), [], []);
+        let delay_ping_pong = self.create_node(modules::ConstCtrl::new(0.0), [], []);
+        let delay_return = self.create_node(modules::SmoothCtrl::new(1.0), [], []);
+
+        let reverb_size = self.create_node(modules::SmoothCtrl::new(0.5), [], []);
+        let reverb_damping = self.create_node(modules::SmoothCtrl::new(0.5), [], []);
+        let reverb_return = self.create_node(modules::SmoothCtrl::new(1.0), [], []);
+
+        let fx_map = FxMap {
+            delay_time,
+            delay_feedback,
+            delay_damping,
+            delay_ping_pong,
+            delay_return,
+            reverb_size,
+            reverb_damping,
+            reverb_return,
+            delay: self.id_alloc.alloc(),
+            reverb: self.id_alloc.alloc(),
+            sends: mixer,
+        };
+        self.update_fx_nodes(&fx_map);
+        let (delay, reverb) = (fx_map.delay, fx_map.reverb);
+        (fx_map, vec![(delay, 0), (delay, 1), (reverb, 0), (reverb, 1)])
+    }
+
+    /// Create the delay and reverb nodes, or replace them with fresh ones
+    /// to clear their tails.
+    fn update_fx_nodes(&mut self, fx_map: &FxMap) {
+        let sample_rate = self.sample_rate;
+        let sends = fx_map.sends;
+        let wiring = vec![
+            (fx_map.delay_time, 0),
+            (fx_map.delay_feedback, 0),
+            (fx_map.delay_damping, 0),
+            (fx_map.delay_ping_pong, 0),
+            (fx_map.delay_return, 0),
+        ];
+        let module = Box::new(dsp::Delay::new(sample_rate));
+        self.send_node(Node::create(module, fx_map.delay, [(sends, 2), (sends, 3)], wiring));
+
+        let wiring = vec![
+            (fx_map.reverb_size, 0),
+            (fx_map.reverb_damping, 0),
+            (fx_map.reverb_return, 0),
+        ];
+        let module = Box::new(dsp::Reverb::new(sample_rate));
+        self.send_node(Node::create(module, fx_map.reverb, [(sends, 4), (sends, 5)], wiring));
+    }
+
+    fn update_mixer_node(
+        &mut self,
+        mixer_node: usize,
+        outputs: &[usize],
+        ctrl_wiring: Vec<(usize, usize)>,
+    ) {
+        let module = Box::new(dsp::Mixer::new(outputs.len()));
+        let buf_wiring: Vec<_> = outputs.iter().flat_map(|&n| vec![(n, 0), (n, 1)]).collect();
+        self.send_node(Node::create(module, mixer_node, buf_wiring, ctrl_wiring));
+    }
+
     fn instantiate_module(&mut self, _node_id: NodeId, ty: ModuleType) -> usize {
         let ll_id = match ty {
             ModuleType::Sin => {
@@ -1,6 +1,72 @@
-
-
-
-pub struct CtrlEvent{
-    
-}
\ No newline at end of file
+use std::ops::Range;
+
+use crate::engine::Param;
+use crate::sequencer::{Condition, Groove, RecordMode};
+
+/// Every step of a sequence, ranges are cut to the sequence length.
+pub const ALL_STEPS: Range<usize> = 0..usize::MAX;
+
+/// Commands for the control thread that aren't notes or sound settings,
+/// from MIDI and the panel. Steps count from 0.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CtrlEvent {
+    /// Start the sequencers from the first step, after a count-in when
+    /// recording.
+    Play,
+    /// Carry on from the step after the last one played, if stopped.
+    Continue,
+    Stop,
+    TogglePlay,
+    /// Arm or disarm recording into the selected channel: in real time while
+    /// playing, a step per chord while stopped.
+    ToggleRecord,
+    SetRecordMode(RecordMode),
+    NextRecordMode,
+    /// Bars of count-in before recording.
+    SetCountIn(usize),
+    /// Play the steps with a fill condition, while held.
+    Fill(bool),
+    /// How much of the groove the channels without their own play, 0..1.
+    SetSwing(f32),
+    SetGroove(Groove),
+    /// Swing of one channel, None to follow the global one.
+    SetChannelSwing { channel: usize, amount: Option<f32> },
+    SetChannelGroove { channel: usize, groove: Option<Groove> },
+    /// Lock the sound controls on the edit step instead of changing them,
+    /// while held.
+    ParamLock(bool),
+
+    /// Move the step being edited, wrapping around the sequence of the
+    /// selected channel.
+    MoveEditStep(isize),
+    SelectEditStep(usize),
+
+    SetNote { channel: usize, step: usize, note: f32, velocity: f32 },
+    ToggleNote { channel: usize, step: usize, note: f32, velocity: f32 },
+    ClearStep { channel: usize, step: usize },
+    /// Set the velocity of every note of a step.
+    SetVelocity { channel: usize, step: usize, velocity: f32 },
+    /// Set the fraction of a step its notes are held for.
+    SetGate { channel: usize, step: usize, gate: f32 },
+    /// Hold the notes of the step before through a step.
+    SetTie { channel: usize, step: usize, tie: bool },
+    /// Glide from a step into the next one.
+    SetSlide { channel: usize, step: usize, slide: bool },
+    /// Play a step early (negative) or late, in steps.
+    SetNudge { channel: usize, step: usize, nudge: f32 },
+    /// Set the chance of a step playing, 0..1.
+    SetProbability { channel: usize, step: usize, probability: f32 },
+    SetCondition { channel: usize, step: usize, condition: Condition },
+    /// Set how many times a step's notes play within the step.
+    SetRatchet { channel: usize, step: usize, ratchet: usize },
+    /// Override a channel parameter while a step plays.
+    SetLock { channel: usize, step: usize, param: Param, value: f32 },
+    ClearLocks { channel: usize, step: usize },
+    /// Copy steps to the clipboard, shared by all channels.
+    Copy { channel: usize, steps: Range<usize> },
+    Paste { channel: usize, step: usize },
+    /// Rotate steps later by `offset`, or earlier if it's negative.
+    Shift { channel: usize, steps: Range<usize>, offset: isize },
+    Transpose { channel: usize, steps: Range<usize>, semitones: f32 },
+    ClearChannel { channel: usize },
+}
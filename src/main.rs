@@ -17,196 +17,300 @@ extern crate midir;
 
 extern crate time;
 
+#[macro_use]
+extern crate log;
+
 extern crate synthesizer_io_core;
 
+mod audio;
+mod clock;
 mod engine;
 mod midi;
 mod note;
 mod sequencer;
 mod config;
+mod control;
+mod diagnostics;
+mod dsp;
 mod serial;
 mod input;
-
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::SampleRate;
+mod project;
+mod render;
+mod rng;
+mod tuning;
 
 
 use synthesizer_io_core::modules;
 use synthesizer_io_core::graph::{IntoBoxedSlice, Message, Node, Note, SetParam};
-use synthesizer_io_core::module::{Module, N_SAMPLES_PER_CHUNK};
+use synthesizer_io_core::module::Module;
 use synthesizer_io_core::queue::Sender;
 use synthesizer_io_core::worker::Worker;
 use std::error::Error;
 use midir::MidiInput;
 
-use std::sync::{Arc, Mutex, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use time::{Duration, Instant};
+use audio::{AudioOptions, AudioOutput};
+use clock::Clock;
+use config::{Command, Config};
+use control::{Control, ControlSender, Event};
+use diagnostics::Diagnostics;
+use dsp::Meter;
 use engine::Engine;
 use midi::Midi;
-use note::{NoteModule, NoteEvent};
-use sequencer::Sequencer;
-use serial::{Serial};
-use input::{CtrlEvent};
+use note::NoteModule;
+use project::Project;
+
+/// How often statistics are logged in diagnostics mode.
+const DIAGNOSTICS_PERIOD: std::time::Duration = std::time::Duration::from_secs(5);
 
 fn main() {
-    let (worker, tx, rx) = Worker::create(4096);
+    let command = match Config::from_args(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let result = match command {
+        Command::Run(config) => run(config),
+        Command::Help(usage) => {
+            println!("{}", usage);
+            Ok(())
+        }
+        Command::ListAudioDevices => audio::list_devices()
+            .map(|names| names.iter().for_each(|name| println!("{}", name)))
+            .map_err(|e| format!("can't list audio devices: {}", e)),
+        Command::ListMidiPorts => Midi::list_midi_ports()
+            .map(|names| names.iter().for_each(|name| println!("{}", name)))
+            .map_err(|e| format!("can't list MIDI ports: {}", e)),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
+}
 
-    let mut engine = Engine::new(config::SAMPLE_HZ, rx, tx);
-    engine.init_polysynth();
-    engine.set_current_channel(1);
-    let engine = Arc::new(Mutex::new(engine));
+/// Set up logging from a filter like `info,midi=debug`, where module names
+/// are relative to this crate.
+fn init_logging(filter: &str) {
+    let crate_name = module_path!();
+    let filter: Vec<String> = filter
+        .split(',')
+        .map(|directive| {
+            if directive.contains('=') && !directive.starts_with(crate_name) {
+                format!("{}::{}", crate_name, directive)
+            } else {
+                directive.to_string()
+            }
+        })
+        .collect();
+    env_logger::Builder::new()
+        .parse_filters(&filter.join(","))
+        .init();
+}
 
+fn run(config: Config) -> Result<(), String> {
+    init_logging(&config.log_level);
+    let diagnostics = Arc::new(Diagnostics::new());
+    if config.diagnostics {
+        let diagnostics = diagnostics.clone();
+        std::thread::spawn(move || {
+            diagnostics::run_reporter(diagnostics, DIAGNOSTICS_PERIOD);
+        });
+    }
 
-    let (ctrl_ch_tx, ctrl_ch_rx) = mpsc::channel::<CtrlEvent>();
-    let note_module = NoteModule::new();
-    let note_module = Arc::new(Mutex::new(note_module));
-    let note_module_cl = note_module.clone();
-    let engine_cl = engine.clone();
-    std::thread::spawn(move || {
-        run_midi(note_module, engine);
-    }); 
+    let project = match config.project {
+        Some(ref path) => Project::load(path)?,
+        None => Project::default(),
+    };
 
-    std::thread::spawn(move || { 
-        run_sequencer(note_module_cl, engine_cl, ctrl_ch_rx);
-    }); 
+    if let Some(ref path) = config.render {
+        render::render(&config, &project, path)?;
+        info!("Rendered {} bars to {}", config.bars, path.display());
+        return Ok(());
+    }
+    let tunings = tuning::load_tunings(&config)?;
 
-    run_cpal(worker);
-}
+    let output = if config.headless {
+        None
+    } else {
+        let audio_options = AudioOptions {
+            device: config.audio_device.clone(),
+            sample_rate: config.sample_hz as u32,
+            buffer_size: config.buffer_size,
+        };
+        let output = AudioOutput::open(&audio_options)
+            .map_err(|e| format!("can't open audio output: {}", e))?;
+        info!("Format: {}", output.describe());
+        Some(output)
+    };
+    let sample_rate = output.as_ref().map_or(config.sample_hz, |o| o.sample_rate());
+
+    let (worker, tx, rx) = Worker::create(4096);
+
+    let mut engine = Engine::new(sample_rate, rx, tx);
+    engine.init_synth(config.synth, config.channel_count, config.voice_count);
+    engine.set_current_channel(config.channel);
+    engine.set_tempo(project.bpm, 0);
+    let clock = engine.get_clock();
+    let meter = engine.meter();
+
+    let mut note_module = NoteModule::new(config.channel_count, config.voice_count);
+    note_module.configure(&mut engine, &config);
+    note_module.set_tunings(tunings, &config.channel_tunings);
+    note_module.set_stuck_timeout(config.stuck_note_timeout_ns());
+    let seed = config.seed.unwrap_or_else(Clock::host_now);
+    let sequencers = sequencer::create_sequencers(&config, &project, seed);
+    let autosave = config.autosave.clone();
+    let mut control = Control::new(engine, note_module, sequencers, diagnostics.clone(), autosave);
+    control.set_count_in(config.count_in);
+    control.set_swing(config.swing());
+    let midi_tx = control.add_producer();
+    let serial_tx = config.serial_port.as_ref().map(|_| control.add_producer());
+
+    // set by SIGINT/SIGTERM, the input threads and the control thread wind
+    // down, the audio keeps going until the control thread is done
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let audio_stop = Arc::new(AtomicBool::new(false));
 
+    let shutdown_control = shutdown.clone();
+    let control = std::thread::spawn(move || control.run(&shutdown_control));
+    let control_thread = control.thread().clone();
 
-fn run_sequencer ( note_module : Arc<Mutex<NoteModule>>, engine : Arc<Mutex<Engine>>, ctrl_ch : mpsc::Receiver<CtrlEvent>){
+    let shutdown_signal = shutdown.clone();
+    let control_signal = control_thread.clone();
+    ctrlc::set_handler(move || {
+        if shutdown_signal.swap(true, Ordering::SeqCst) {
+            // second signal, stop waiting
+            std::process::exit(130);
+        }
+        control_signal.unpark();
+    })
+    .map_err(|e| format!("can't install signal handler: {}", e))?;
 
-    let mut sequencers : Vec<Arc<Mutex<Sequencer>>> = vec![];
-    for channel in 1..config::CHANNEL_COUNT {
-        let sequencer = Sequencer::new(channel, 120.0, 8);
-        let mutx = Arc::new(Mutex::new(sequencer));
-        sequencers.push(mutx);
+    let mut inputs = vec![];
+    if let Some(ref port_name) = config.serial_port {
+        let port = serial::open_port(port_name, config.serial_baud)?;
+        let sender = ControlSender::new(serial_tx.unwrap(), control_thread.clone());
+        let clock = clock.clone();
+        let shutdown = shutdown.clone();
+        inputs.push(std::thread::spawn(move || {
+            run_serial(sender, clock, port, &shutdown);
+        }));
     }
 
-    for channel in 1..config::CHANNEL_COUNT {
-        let mut engine_cl = engine.clone();
-        let mut note_module_cl = note_module.clone();
-        let sequencer = sequencers[channel - 1].clone();
-        std::thread::spawn(move | | {
-            
-            let mut current_time = Instant::now();
-            let mut tick = false;
-            let mut residue : i128 = 0;
-            let mut cum_error = 0;
-            loop{
-                let elapsed_time = current_time.elapsed().whole_microseconds();
-                let mut sequencer = sequencer.lock().unwrap();
-                let microseconds = ((30.0 / (&sequencer.get_bpm()))*1000_000.0) as i128;
-
-                
-                if elapsed_time >= microseconds - residue{
-                    residue = elapsed_time - microseconds + residue;
-                    println!("{:?} {:?}",residue, elapsed_time);
-                    
-                    let mut note_module = note_module_cl.lock().unwrap();
-                    let mut engine = engine_cl.lock().unwrap();
-                    let current_channel : usize = engine.get_current_channel();
-
-                    if tick {
-                        sequencer.tick(&mut engine, &mut note_module);
-                    }else{
-                        if channel == current_channel {
-                            sequencer.update_notes(&mut note_module);
-                        }
-                        sequencer.tock(&mut engine, &mut note_module);
-                    }
-                    
-                    tick = !tick;
-                    current_time = Instant::now();
-                    cum_error = cum_error + elapsed_time - microseconds;
-                    std::thread::sleep(std::time::Duration::from_micros((microseconds as f32 * 0.1 ) as u64));
-                }
-                //std::thread::sleep(std::time::Duration::from_micros(10));
-            }
-        });
+    let midi_port = config.midi_port.clone();
+    let sender = ControlSender::new(midi_tx, control_thread);
+    let clock_midi = clock.clone();
+    let diagnostics_midi = diagnostics.clone();
+    let shutdown_midi = shutdown.clone();
+    inputs.push(std::thread::spawn(move || {
+        run_midi(sender, clock_midi, midi_port, diagnostics_midi, &shutdown_midi);
+    }));
+
+    let diagnostics_monitor = diagnostics.clone();
+    std::thread::spawn(move || {
+        run_monitor(meter, diagnostics_monitor);
+    });
+
+    let buffer_size = config.buffer_size.unwrap_or(512);
+    let audio_stop_cl = audio_stop.clone();
+    let audio = std::thread::spawn(move || match output {
+        Some(output) => output.run(worker, clock, diagnostics, &audio_stop_cl),
+        None => audio::run_headless(worker, clock, buffer_size, &audio_stop_cl),
+    });
+
+    if control.join().is_err() {
+        error!("control thread panicked");
+    }
+    audio_stop.store(true, Ordering::SeqCst);
+    for input in inputs {
+        let _ = input.join();
     }
-    let engine_cl = engine.clone();
-    let note_module_cl = note_module.clone();
+    let _ = audio.join();
+    Ok(())
+}
+
+/// Once a second, log clipping and audio dropouts.
+fn run_monitor(meter : Option<Arc<Meter>>, diagnostics : Arc<Diagnostics>) {
+    let mut reported = (0, 0);
     loop {
-        let ctrl_event = ctrl_ch.recv().unwrap();
-        
-        std::thread::sleep(std::time::Duration::from_micros(1000));
+        std::thread::sleep(std::time::Duration::from_millis(1000));
+
+        let (xruns, overruns) = diagnostics.audio_problems();
+        if (xruns, overruns) != reported {
+            warn!(
+                "audio dropouts: {} xruns, {} callback overruns in the last second",
+                xruns - reported.0, overruns - reported.1
+            );
+            reported = (xruns, overruns);
+        }
+
+        if let Some(reading) = meter.as_ref().map(|meter| meter.take()) {
+            if reading.overs > 0 {
+                let db = |level: f32| 20.0 * level.max(1e-6).log10();
+                warn!(
+                    "master limiting: peak {:.1}/{:.1} dBFS, gain reduction {:.1} dB, {} overs",
+                    db(reading.peak[0]), db(reading.peak[1]), -db(reading.min_gain), reading.overs
+                );
+            }
+        }
     }
 }
 
-fn run_midi( note_module : Arc<Mutex<NoteModule>>, engine : Arc<Mutex<Engine>>){
+fn run_midi( control : ControlSender, clock : Arc<Clock>, port_name : Option<String>, diagnostics : Arc<Diagnostics>, shutdown : &AtomicBool){
     // midi setup
     
-    let mut midi_in = MidiInput::new("midir input").expect("can't create midi input");
-    let mut midi = Midi::new();
-    let mut in_port = Midi::find_midi_port(&midi_in).unwrap();
+    let mut midi_in = match MidiInput::new("midir input") {
+        Ok(midi_in) => midi_in,
+        Err(e) => {
+            warn!("can't create midi input, running without MIDI: {}", e);
+            return;
+        }
+    };
+    let in_port = match Midi::find_midi_port(&midi_in, port_name.as_deref()) {
+        Ok(in_port) => in_port,
+        Err(e) => {
+            warn!("{}, running without MIDI", e);
+            return;
+        }
+    };
     midi_in.ignore(::midir::Ignore::None);
-    let result = midi_in.connect(
+    let connection = midi_in.connect(
         &in_port,
         "midir-read-input",
-        move |ts, data, _| {
-            let mut engine = engine.lock().unwrap();
-            let mut note_module = note_module.lock().unwrap();
-            Midi::dispatch_midi(&mut *note_module, &mut *engine, data, ts);
+        move |_, data, _| {
+            diagnostics.record_midi_event();
+            control.send(Event::Midi(data.to_vec(), clock.now()));
         }, 
         (),
     );
-    if let Err(e) = result {
-        println!("error connecting to midi: {:?}", e);
-    }
-    loop {
-
-        std::thread::sleep(std::time::Duration::from_millis(10000));
+    let connection = match connection {
+        Ok(connection) => connection,
+        Err(e) => {
+            error!("error connecting to midi: {:?}", e);
+            return;
+        }
+    };
+    while !shutdown.load(Ordering::SeqCst) {
+        std::thread::sleep(std::time::Duration::from_millis(100));
     }
+    connection.close();
 }
 
-fn run_cpal(mut worker: Worker) {
-    let host = cpal::default_host();
-    let device = host
-        .default_output_device()
-        .expect("no output device available");
-    let mut supported_configs_range = device
-        .supported_output_configs()
-        .expect("error while querying configs");
-    let supported_config = supported_configs_range
-        .next()
-        .expect("no supported config?!")
-        .with_sample_rate(SampleRate(config::SAMPLE_HZ as u32));
-
-    let config = supported_config.into();
-    println!("Format: {:?}",config);
-    
-    let stream = device.build_output_stream(
-        &config,
-        move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
-            //info.timestamp().callback().unwrap()
-            let mut buf_slice = data;
-            let mut i = 0;
-            let mut timestamp = time::precise_time_ns();
-            while i < buf_slice.len() {
-                // should let the graph generate stereo
-                let buf = worker.work(timestamp)[0].get();
-                for j in 0..N_SAMPLES_PER_CHUNK {
-                    buf_slice[i + j * 2] = buf[j];
-                    buf_slice[i + j * 2 + 1] = buf[j];
-                }
-
-                // TODO: calculate properly, magic value is 64 * 1e9 / 44_100
-                timestamp += 1451247 * (N_SAMPLES_PER_CHUNK as u64) / 64;
-                i += N_SAMPLES_PER_CHUNK * 2;
+fn run_serial( control : ControlSender, clock : Arc<Clock>, mut port : Box<dyn serialport::SerialPort>, shutdown : &AtomicBool){
+    let mut serial_buf: Vec<u8> = vec![0; 64];
+    while !shutdown.load(Ordering::SeqCst) {
+        match serial::read_port(&mut *port, serial_buf.as_mut_slice()) {
+            Ok(0) => {}
+            Ok(n) => control.send(Event::Serial(serial_buf[..n].to_vec(), clock.now())),
+            Err(e) => {
+                error!("error reading serial port, closing it: {}", e);
+                return;
             }
-                
-               
-            
-        },
-        move |err| {
-            // react to errors here.
-        },
-    ).expect("Failed to build audio stream");
-
-    stream.play().expect("Failed to play stream");
-    loop {
-        std::thread::sleep(std::time::Duration::from_millis(10000));
+        }
+        std::thread::sleep(std::time::Duration::from_millis(5));
     }
-}
\ No newline at end of file
+}
@@ -1,8 +1,12 @@
 
-use crate::engine::{ControlMap, Engine};
-use crate::note::{NoteModule, NoteEvent};
+use crate::engine::{ControlMap, Engine, Param, VoiceExpression};
+use crate::input::{CtrlEvent, ALL_STEPS};
+use crate::sequencer::{Condition, Groove, RecordMode, MAX_RATCHET, MIN_GATE};
+use crate::note::{Allocation, Mono, NoteModule, NoteEvent, Priority};
+use crate::dsp::{FilterMode, MAX_DELAY_SECONDS};
 
 
+use log::{debug, info, trace};
 use midir::{MidiInput, MidiInputPort, MidiInputConnection, ConnectError};
 use synthesizer_io_core::graph::{Message, SetParam};
 
@@ -14,93 +18,419 @@ use std::sync::{Arc, Mutex, mpsc};
 pub struct Midi {
 }
 
+/// Delay times selectable when the delay follows the tempo, in beats.
+const DELAY_SYNC_BEATS: [f32; 8] = [0.25, 0.5, 0.75, 1.0, 1.5, 2.0, 3.0, 4.0];
+
+/// Most unison voices reachable from the unison controller.
+const MAX_UNISON: usize = 8;
+
 
 impl Midi {
     pub fn new() -> Midi {
         Midi { }
     }
 
-    pub fn dispatch_midi(note_module : &mut NoteModule, engine: &mut Engine, data: &[u8], ts: u64) {
+    /// Play notes and change settings from MIDI data. Sequencer edits are
+    /// returned for the control thread to apply.
+    pub fn dispatch_midi(note_module : &mut NoteModule, engine: &mut Engine, data: &[u8], ts: u64) -> Vec<CtrlEvent> {
         let mut i = 0;
+        let mut edits = vec![];
         let channel = engine.get_current_channel();
+        let step = engine.get_edit_step();
         let control_map : ControlMap = engine.get_current_control_map();
+        let fx_map = engine.get_fx_map();
         
         while i < data.len() {
-            println!("{},{},{}", data[i], data[i + 1], data[i + 2]);
-            if data[i] == 0xb0 {
+            let len = Midi::message_len(&data[i..]);
+            trace!("{:?}", &data[i..data.len().min(i + len)]);
+            if i + len > data.len() {
+                debug!("truncated midi message {:?}", &data[i..]);
+                break;
+            }
+            let status = data[i] & 0xf0;
+            // in MPE mode, MIDI channels 2-16 each play a single voice
+            let midi_channel = (data[i] & 0x0f) as usize;
+            let member = note_module.mpe() && status < 0xf0 && midi_channel != 0;
+            let range = if status == 0xb0 && !member {
+                Midi::param_range(data[i + 1], note_module.mod_wheel_vibrato())
+            } else {
+                None
+            };
+
+            if status == 0xb0 && member {
+                if data[i + 1] == 74 {
+                    if let Some(voices) = note_module.mpe_voices(midi_channel) {
+                        let value = Midi::midi_value_to_float(data[i + 2]);
+                        engine.set_voice_expression(0, voices, VoiceExpression::Slide, value, ts);
+                    }
+                }
+            } else if let Some((param, lo, hi)) = range {
+                let value = Midi::midi_value_to_float(data[i + 2]);
+                if engine.get_param_lock() {
+                    // locked on the edit step instead
+                    let value = lo + value * (hi - lo);
+                    edits.push(CtrlEvent::SetLock { channel, step, param, value });
+                } else {
+                    engine.set_ctrl_const(value, lo, hi, control_map.param(param), ts);
+                }
+            } else if status == 0xb0 {
                 let controller = data[i + 1];
                 let value = Midi::midi_value_to_float(data[i + 2]);
                 
                 match controller {
                     1 => {
-                        let cutoff = control_map.cutoff;
-                        engine.set_ctrl_const(value, 0.0, 22_000f32.log2(), cutoff, ts);
+                        engine.set_mod_wheel(0, value, ts);
                     }
-                    2 => {
-                        let reso = control_map.reso;
-                        engine.set_ctrl_const(value, 0.0, 0.995, reso, ts);
+                    3 => {
+                        engine.set_filter_mode(channel, FilterMode::from_ctrl(value), ts);
                     }
-
-                    5 => {
-                        let attack = control_map.attack;
-                        engine.set_ctrl_const(value, 0.0, 10.0, attack, ts);
+                    15 => {
+                        engine.set_mute(channel, value >= 0.5, ts);
+                    }
+                    16 => {
+                        engine.set_solo(channel, value >= 0.5, ts);
+                    }
+                    17 => {
+                        engine.set_master_gain(value, ts);
+                    }
+                    18 => {
+                        if let Some(master_map) = engine.get_master_map() {
+                            engine.set_ctrl_const(value, -12.0, 0.0, master_map.ceiling, ts);
+                        }
+                    }
+                    // limiter release time, CC9 is otherwise undefined
+                    9 => {
+                        if let Some(master_map) = engine.get_master_map() {
+                            engine.set_ctrl_const(value, 0.01, 1.0, master_map.release, ts);
+                        }
+                    }
+                    19 => {
+                        if value >= 0.5 {
+                            info!("Panic");
+                            note_module.panic(engine, ts);
+                        }
+                    }
+                    20 => {
+                        let allocation = Allocation::from_ctrl(value);
+                        debug!("channel {} voice allocation {:?}", channel, allocation);
+                        note_module.set_allocation(channel, allocation);
+                    }
+                    21 => {
+                        // squared, so short glides get most of the range
+                        engine.set_glide(channel, value * value * 2.0, ts);
+                    }
+                    22 => {
+                        let legato = note_module.get_mono(channel).map_or(false, |m| m.legato);
+                        let mono = match (value * 4.0) as usize {
+                            0 => None,
+                            1 => Some(Priority::Last),
+                            2 => Some(Priority::Low),
+                            _ => Some(Priority::High),
+                        }
+                        .map(|priority| Mono { priority, legato });
+                        debug!("channel {} mono {:?}", channel, mono);
+                        note_module.set_mono(engine, channel, mono, ts);
+                    }
+                    23 => {
+                        if let Some(mut mono) = note_module.get_mono(channel) {
+                            mono.legato = value >= 0.5;
+                            note_module.set_mono(engine, channel, Some(mono), ts);
+                        }
+                    }
+                    24..=26 => {
+                        let mut unison = note_module.get_unison(channel);
+                        match controller {
+                            24 => unison.voices = 1 + (value * (MAX_UNISON - 1) as f32).round() as usize,
+                            25 => unison.detune = value,
+                            _ => unison.spread = value,
+                        }
+                        debug!("channel {} unison {:?}", channel, unison);
+                        note_module.set_unison(engine, channel, unison, ts);
+                    }
+                    28 => {
+                        let last = note_module.tuning_count() - 1;
+                        let index = (value * last as f32).round() as usize;
+                        debug!("channel {} tuning {}", channel, index);
+                        note_module.set_tuning(channel, index);
+                    }
+                    // timing of the selected channel
+                    29 => edits.push(CtrlEvent::SetChannelSwing { channel, amount: Some(value) }),
+                    30 if value >= 0.5 => {
+                        edits.push(CtrlEvent::SetChannelSwing { channel, amount: None });
+                        edits.push(CtrlEvent::SetChannelGroove { channel, groove: None });
+                    }
+                    31 => {
+                        // centered on 64
+                        let nudge = (data[i + 2] as f32 - 64.0) / 128.0;
+                        edits.push(CtrlEvent::SetNudge { channel, step, nudge });
+                    }
+                    // trig conditions
+                    75 => edits.push(CtrlEvent::Fill(value >= 0.5)),
+                    76 => edits.push(CtrlEvent::SetProbability { channel, step, probability: value }),
+                    77 => {
+                        let count = Condition::PRESETS.len();
+                        let index = ((value * count as f32) as usize).min(count - 1);
+                        let condition = Condition::PRESETS[index];
+                        edits.push(CtrlEvent::SetCondition { channel, step, condition });
+                    }
+                    78 => {
+                        let ratchet = 1 + (value * (MAX_RATCHET - 1) as f32).round() as usize;
+                        edits.push(CtrlEvent::SetRatchet { channel, step, ratchet });
+                    }
+                    // parameter locks, held down to lock
+                    79 => edits.push(CtrlEvent::ParamLock(value >= 0.5)),
+                    80 if value >= 0.5 => edits.push(CtrlEvent::ClearLocks { channel, step }),
+                    84 => {
+                        let index = (value * (DELAY_SYNC_BEATS.len() - 1) as f32).round() as usize;
+                        engine.set_delay_sync(DELAY_SYNC_BEATS[index], ts);
+                    }
+                    85 => {
+                        engine.set_delay_time(value * MAX_DELAY_SECONDS, ts);
+                    }
+                    86 => {
+                        if let Some(ref fx_map) = fx_map {
+                            engine.set_ctrl_const(value, 0.0, 0.95, fx_map.delay_feedback, ts);
+                        }
+                    }
+                    87 => {
+                        if let Some(ref fx_map) = fx_map {
+                            let (lo, hi) = (200f32.log2(), 16_000f32.log2());
+                            engine.set_ctrl_const(value, lo, hi, fx_map.delay_damping, ts);
+                        }
+                    }
+                    88 => {
+                        engine.set_delay_ping_pong(value >= 0.5, ts);
+                    }
+                    89 => {
+                        if let Some(ref fx_map) = fx_map {
+                            engine.set_ctrl_const(value, 0.0, 1.0, fx_map.reverb_size, ts);
+                        }
                     }
-                    6 => {
-                        let decay = control_map.decay;
-                        engine.set_ctrl_const(value, 0.0, 10.0, decay, ts);
+                    90 => {
+                        if let Some(ref fx_map) = fx_map {
+                            engine.set_ctrl_const(value, 0.0, 1.0, fx_map.reverb_damping, ts);
+                        }
                     }
-                    7 => {
-                        let sustain = control_map.sustain;
-                        engine.set_ctrl_const(value, 0.0, 6.0, sustain, ts);
+                    // step editing on the selected channel
+                    102 => edits.push(CtrlEvent::SelectEditStep(data[i + 2] as usize)),
+                    103 if value >= 0.5 => {
+                        // the notes held on the keyboard
+                        for voice in note_module.get_voices(0).iter() {
+                            if let Some(note) = voice.note {
+                                let velocity = voice.velocity;
+                                edits.push(CtrlEvent::ToggleNote { channel, step, note, velocity });
+                            }
+                        }
                     }
-                    8 => {
-                        let release = control_map.release;
-                        engine.set_ctrl_const(value, 0.0, 10.0, release, ts);
+                    104 if value >= 0.5 => edits.push(CtrlEvent::ClearStep { channel, step }),
+                    105 => {
+                        let velocity = data[i + 2].max(1) as f32;
+                        edits.push(CtrlEvent::SetVelocity { channel, step, velocity });
                     }
-                    _ => println!("don't have handler for controller {}", controller),
+                    106 if value >= 0.5 => edits.push(CtrlEvent::Copy { channel, steps: step..step + 1 }),
+                    107 if value >= 0.5 => edits.push(CtrlEvent::Paste { channel, step }),
+                    108 => {
+                        let offset = if value < 0.5 { -1 } else { 1 };
+                        edits.push(CtrlEvent::Shift { channel, steps: ALL_STEPS, offset });
+                    }
+                    109 => {
+                        let semitones = if value < 0.5 { -1.0 } else { 1.0 };
+                        edits.push(CtrlEvent::Transpose { channel, steps: ALL_STEPS, semitones });
+                    }
+                    110 if value >= 0.5 => edits.push(CtrlEvent::ClearChannel { channel }),
+                    // transport and recording
+                    111 if value >= 0.5 => edits.push(CtrlEvent::TogglePlay),
+                    112 if value >= 0.5 => edits.push(CtrlEvent::ToggleRecord),
+                    113 => {
+                        let index = (value * RecordMode::COUNT as f32) as usize;
+                        let mode = RecordMode::from_index(index.min(RecordMode::COUNT - 1));
+                        edits.push(CtrlEvent::SetRecordMode(mode));
+                    }
+                    114 => edits.push(CtrlEvent::SetCountIn((value * 4.0).round() as usize)),
+                    // note length of the step being edited
+                    115 => {
+                        let gate = MIN_GATE + value * (1.0 - MIN_GATE);
+                        edits.push(CtrlEvent::SetGate { channel, step, gate });
+                    }
+                    116 => edits.push(CtrlEvent::SetTie { channel, step, tie: value >= 0.5 }),
+                    117 => edits.push(CtrlEvent::SetSlide { channel, step, slide: value >= 0.5 }),
+                    // timing
+                    118 => edits.push(CtrlEvent::SetSwing(value)),
+                    119 => {
+                        let index = (value * Groove::COUNT as f32) as usize;
+                        edits.push(CtrlEvent::SetGroove(Groove::from_index(index.min(Groove::COUNT - 1))));
+                    }
+                    102..=119 => {}
+                    // all sound off, all notes off: for the live channel
+                    // and the selected one
+                    120 | 123 => {
+                        for &ch in [0, channel].iter() {
+                            if controller == 120 {
+                                note_module.all_sound_off(engine, ch, ts);
+                            } else {
+                                note_module.all_notes_off(engine, ch, ts);
+                            }
+                        }
+                    }
+                    _ => debug!("don't have handler for controller {}", controller),
                 }
-                i += 3;
-            } else if data[i] == 0x90 || data[i] == 0x80 {
+            } else if status == 0x90 || status == 0x80 {
                 let midi_num = data[i + 1] as f32;
                 let velocity = data[i + 2] as f32;
-                let on = data[i] == 0x90 && velocity > 0.0;
+                let on = status == 0x90 && velocity > 0.0;
                 let note_event = NoteEvent{down: on, note : midi_num, velocity : velocity, timestamp: ts};
 
-                note_module.note_event(engine, note_event, 0);
-                i += 3;
+                if member {
+                    note_module.mpe_note_event(engine, note_event, midi_channel);
+                } else {
+                    note_module.note_event(engine, note_event, 0);
+                }
+            } else if status == 0xe0 {
+                let bend = Midi::bend_value(data[i + 1], data[i + 2]);
+                if member {
+                    if let Some(voices) = note_module.mpe_voices(midi_channel) {
+                        engine.set_voice_expression(0, voices, VoiceExpression::Bend, bend, ts);
+                    }
+                } else {
+                    engine.set_bend(0, bend, ts);
+                }
+            } else if status == 0xd0 {
+                let pressure = Midi::midi_value_to_float(data[i + 1]);
+                if member {
+                    if let Some(voices) = note_module.mpe_voices(midi_channel) {
+                        engine.set_voice_expression(0, voices, VoiceExpression::Pressure, pressure, ts);
+                    }
+                } else {
+                    engine.set_pressure(0, pressure, ts);
+                }
+            } else if status == 0xa0 {
+                let pressure = Midi::midi_value_to_float(data[i + 2]);
+                for voices in note_module.voices_playing(0, data[i + 1] as f32) {
+                    engine.set_voice_expression(0, voices, VoiceExpression::Pressure, pressure, ts);
+                }
+            } else if data[i] == 0xfa {
+                // start
+                edits.push(CtrlEvent::Play);
+            } else if data[i] == 0xfb {
+                // continue
+                edits.push(CtrlEvent::Continue);
+            } else if data[i] == 0xfc {
+                edits.push(CtrlEvent::Stop);
+            } else if data[i] == 0xff {
+                // system reset
+                info!("Panic");
+                note_module.panic(engine, ts);
             } else {
-                println!("don't have handler for midi code {}", data[i]);
-                break;
+                debug!("don't have handler for midi code {}", data[i]);
             }
+            i += len;
+        }
+        edits
+    }
+
+    /// The channel parameter a controller sets and the range it covers. In
+    /// lock mode it locks the parameter on the edit step instead.
+    fn param_range(controller: u8, mod_wheel_vibrato: bool) -> Option<(Param, f32, f32)> {
+        match controller {
+            // the cutoff, unless the mod wheel plays vibrato
+            1 if !mod_wheel_vibrato => Some((Param::Cutoff, 0.0, 22_000f32.log2())),
+            2 => Some((Param::Reso, 0.0, 0.995)),
+            4 => Some((Param::Drive, 0.0, 1.0)),
+            5 => Some((Param::Attack, 0.0, 10.0)),
+            6 => Some((Param::Decay, 0.0, 10.0)),
+            7 => Some((Param::Sustain, 0.0, 6.0)),
+            8 => Some((Param::Release, 0.0, 10.0)),
+            10 => Some((Param::Pan, -1.0, 1.0)),
+            11 => Some((Param::Volume, 0.0, 1.0)),
+            12 => Some((Param::VelAmp, 0.0, 1.0)),
+            13 => Some((Param::VelCutoff, 0.0, 4.0)),
+            14 => Some((Param::VelCurve, -1.0, 1.0)),
+            // how far pressure and slide open the filter
+            27 => Some((Param::ExpressionCutoff, 0.0, 4.0)),
+            74 => Some((Param::Cutoff, 0.0, 22_000f32.log2())),
+            91 => Some((Param::ReverbSend, 0.0, 1.0)),
+            92 => Some((Param::DelaySend, 0.0, 1.0)),
+            _ => None,
         }
     }
 
-    pub fn find_midi_port(midi_in : &MidiInput) -> Result<MidiInputPort, &str>{
+    /// Length of the message starting at `data[0]`, including the status
+    /// byte. A stray data byte counts as a message of its own.
+    fn message_len(data: &[u8]) -> usize {
+        match data[0] {
+            0x80..=0xbf | 0xe0..=0xef | 0xf2 => 3,
+            0xc0..=0xdf | 0xf1 | 0xf3 => 2,
+            // system exclusive, up to and including the end byte
+            0xf0 => data.iter().position(|&b| b == 0xf7).map_or(data.len(), |end| end + 1),
+            _ => 1,
+        }
+    }
+
+    /// A 14 bit pitch bend as -1..1, centered at 0.
+    fn bend_value(lsb: u8, msb: u8) -> f32 {
+        let value = ((msb as i32 & 0x7f) << 7 | (lsb as i32 & 0x7f)) - 0x2000;
+        value as f32 * (1.0 / 8192.0)
+    }
+
+    /// Find an input port by name, or a unique part of it. Without a name,
+    /// the only port is used, or the user is asked to pick one.
+    pub fn find_midi_port(midi_in : &MidiInput, name: Option<&str>) -> Result<MidiInputPort, String>{
         let in_ports = midi_in.ports();
+        let port_name = |p: &MidiInputPort| midi_in.port_name(p).unwrap_or_default();
+        if let Some(name) = name {
+            if let Some(port) = in_ports.iter().find(|p| port_name(p) == name) {
+                return Ok(port.clone());
+            }
+            let matching: Vec<_> = in_ports
+                .iter()
+                .filter(|p| port_name(p).to_lowercase().contains(&name.to_lowercase()))
+                .collect();
+            return match matching.len() {
+                0 => Err(format!("no MIDI input port matching \"{}\"", name)),
+                1 => Ok(matching[0].clone()),
+                _ => Err(format!("more than one MIDI input port matches \"{}\"", name)),
+            };
+        }
         match in_ports.len() {
-            0 => return Err("no input port found"),
+            0 => return Err("no input port found".to_string()),
             1 => {
-                println!(
+                info!(
                     "Choosing the only available input port: {}",
-                    midi_in.port_name(&in_ports[0]).unwrap()
+                    port_name(&in_ports[0])
                 );
                 Ok(in_ports[0].clone())
             }
             _ => {
                 println!("\nAvailable input ports:");
                 for (i, p) in in_ports.iter().enumerate() {
-                    println!("{}: {}", i, midi_in.port_name(p).unwrap());
+                    println!("{}: {}", i, port_name(p));
                 }
                 print!("Please select input port: ");
-                stdout().flush().expect("Failed to flush stdout");
+                stdout().flush().map_err(|e| e.to_string())?;
                 let mut input = String::new();
-                stdin().read_line(&mut input).expect("Failed to read line");
-                Ok(in_ports
-                    .get(input.trim().parse::<usize>().expect("Failed to parse input"))
-                    .expect("invalid input port selected").clone())
+                stdin().read_line(&mut input).map_err(|e| e.to_string())?;
+                let index = input
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| format!("invalid input port \"{}\"", input.trim()))?;
+                in_ports
+                    .get(index)
+                    .cloned()
+                    .ok_or_else(|| format!("invalid input port {}", index))
             }
         }
     }
+
+    /// Names of the available input ports.
+    pub fn list_midi_ports() -> Result<Vec<String>, String> {
+        let midi_in = MidiInput::new("midir input").map_err(|e| e.to_string())?;
+        Ok(midi_in
+            .ports()
+            .iter()
+            .map(|p| midi_in.port_name(p).unwrap_or_default())
+            .collect())
+    }
+
     pub fn setup_midi_connection(){
         
     }
@@ -109,3 +439,26 @@ impl Midi {
         value as f32 * (1.0/127.0) 
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_len_follows_the_status_byte() {
+        assert_eq!(Midi::message_len(&[0x91, 60, 100]), 3);
+        assert_eq!(Midi::message_len(&[0xe3, 0, 64]), 3);
+        assert_eq!(Midi::message_len(&[0xd0, 90, 0x90]), 2);
+        assert_eq!(Midi::message_len(&[0xc5, 1]), 2);
+        assert_eq!(Midi::message_len(&[0xf8, 0x90]), 1);
+        assert_eq!(Midi::message_len(&[0xf0, 1, 2, 0xf7, 0x90]), 4);
+        assert_eq!(Midi::message_len(&[0xf0, 1, 2]), 3);
+    }
+
+    #[test]
+    fn bend_value_is_centered() {
+        assert_eq!(Midi::bend_value(0, 64), 0.0);
+        assert_eq!(Midi::bend_value(0, 0), -1.0);
+        assert!((Midi::bend_value(127, 127) - 1.0).abs() < 1e-3);
+    }
+}
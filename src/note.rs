@@ -1,94 +1,714 @@
-use crate::engine::{ControlMap, Engine};
-use crate::config;
+use crate::config::{Config, SynthMode};
+use crate::engine::{Engine, VoiceExpression};
+use crate::tuning::Tuning;
 
-use std::sync::mpsc;
+use log::warn;
+
+use std::cmp::Ordering;
+use std::ops::Range;
+
+use serde::Deserialize;
 use synthesizer_io_core::graph::{Message, Note};
 
+/// How a channel picks the voice for a new note.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Allocation {
+    /// Cycle through the voices, so releases ring out as long as possible.
+    RoundRobin,
+    /// First free voice, or steal the note played longest ago.
+    Oldest,
+    /// Free voice released longest ago, or steal the softest note.
+    Quietest,
+    /// Like `Oldest`, but a note already sounding is played again on its
+    /// own voice.
+    Retrigger,
+    /// When all voices are busy the lowest notes keep sounding, a note above
+    /// all of them isn't played.
+    LowestNote,
+    /// When all voices are busy the highest notes keep sounding, a note below
+    /// all of them isn't played.
+    HighestNote,
+}
+
+impl Allocation {
+    pub const COUNT: usize = 6;
+
+    pub fn from_index(index: usize) -> Allocation {
+        match index {
+            0 => Allocation::RoundRobin,
+            1 => Allocation::Oldest,
+            2 => Allocation::Quietest,
+            3 => Allocation::Retrigger,
+            4 => Allocation::LowestNote,
+            _ => Allocation::HighestNote,
+        }
+    }
+
+    /// Pick a mode from a 0..1 controller value, giving each mode an equal
+    /// share of the range.
+    pub fn from_ctrl(value: f32) -> Allocation {
+        let index = (value.max(0.0) * Allocation::COUNT as f32) as usize;
+        Allocation::from_index(index.min(Allocation::COUNT - 1))
+    }
+
+    pub fn from_name(name: &str) -> Option<Allocation> {
+        match name {
+            "round-robin" => Some(Allocation::RoundRobin),
+            "oldest" => Some(Allocation::Oldest),
+            "quietest" => Some(Allocation::Quietest),
+            "retrigger" => Some(Allocation::Retrigger),
+            "lowest-note" => Some(Allocation::LowestNote),
+            "highest-note" => Some(Allocation::HighestNote),
+            _ => None,
+        }
+    }
+}
+
+/// Which of the held notes a mono channel plays.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Last,
+    Low,
+    High,
+}
+
+impl Priority {
+    pub fn from_name(name: &str) -> Option<Priority> {
+        match name {
+            "last" => Some(Priority::Last),
+            "low" => Some(Priority::Low),
+            "high" => Some(Priority::High),
+            _ => None,
+        }
+    }
+}
+
+/// Mono playing of a channel. With legato, a note played while another is
+/// held only changes the pitch, the envelope carries on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mono {
+    pub priority: Priority,
+    pub legato: bool,
+}
+
+/// Voices stacked on every note of a channel, spread in pitch and across
+/// the stereo field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Unison {
+    pub voices: usize,
+    /// Pitch offset of the outermost voices, in semitones.
+    pub detune: f32,
+    /// Pan of the outermost voices, from 0 (centered) to 1 (hard left and
+    /// right).
+    pub spread: f32,
+}
+
+impl Unison {
+    pub const OFF: Unison = Unison { voices: 1, detune: 0.0, spread: 0.0 };
+
+    /// Position of voice `index` of the stack, evenly spaced in -1..1.
+    pub fn offset(&self, index: usize) -> f32 {
+        if self.voices < 2 {
+            return 0.0;
+        }
+        2.0 * index as f32 / (self.voices - 1) as f32 - 1.0
+    }
+}
+
+/// What the voices have to be told after a note event. With unison, `voice`
+/// is a group of voices, see `ChannelVoices::group`.
+#[derive(Debug, PartialEq)]
+pub enum VoiceEvent {
+    On { voice: usize, note: f32, velocity: f32 },
+    Off { voice: usize, note: f32 },
+    /// Change the pitch of a sounding voice without retriggering it.
+    Legato { voice: usize, note: f32 },
+}
+
+/// The voices of one channel and their allocation. Notes are given groups
+/// of `unison.voices` voices, so the polyphony is the voice count divided
+/// by that.
+pub struct ChannelVoices {
+    // one per group
+    voices: Vec<Voice>,
+    voice_count: usize,
+    unison: Unison,
+    allocation: Allocation,
+    // voice after the last one assigned, for round robin
+    next: usize,
+    mono: Option<Mono>,
+    // notes held on a mono channel with their velocities, in the order they
+    // were played
+    held_notes: Vec<(f32, f32)>,
+}
+
+impl ChannelVoices {
+    pub fn new(voice_count: usize, allocation: Allocation) -> ChannelVoices {
+        ChannelVoices {
+            voices: vec![NONE_VOICE; voice_count],
+            voice_count,
+            unison: Unison::OFF,
+            allocation,
+            next: 0,
+            mono: None,
+            held_notes: vec![],
+        }
+    }
+
+    pub fn note_on(&mut self, note: f32, velocity: f32, ts: u64) -> Vec<VoiceEvent> {
+        match self.mono {
+            Some(mono) => {
+                self.held_notes.retain(|&(n, _)| n != note);
+                self.held_notes.push((note, velocity));
+                self.update_mono(mono, ts)
+            }
+            None => self.poly_note_on(note, velocity, ts),
+        }
+    }
+
+    pub fn note_off(&mut self, note: f32, ts: u64) -> Vec<VoiceEvent> {
+        match self.mono {
+            Some(mono) => {
+                let count = self.held_notes.len();
+                self.held_notes.retain(|&(n, _)| n != note);
+                if self.held_notes.len() == count {
+                    return vec![];
+                }
+                self.update_mono(mono, ts)
+            }
+            None => self.poly_note_off(note, ts),
+        }
+    }
+
+    /// Assign a voice to a note, stealing one if needed. Nothing happens if
+    /// the allocation mode drops the note.
+    fn poly_note_on(&mut self, note: f32, velocity: f32, ts: u64) -> Vec<VoiceEvent> {
+        let vx = match self.pick_voice(note) {
+            Some(vx) => vx,
+            None => return vec![],
+        };
+        let mut events = vec![];
+        let voice = &mut self.voices[vx];
+        if let Some(replaced) = voice.note {
+            events.push(VoiceEvent::Off { voice: vx, note: replaced });
+        }
+        voice.note = Some(note);
+        voice.velocity = velocity;
+        voice.timestamp = ts;
+        self.next = (vx + 1) % self.voices.len();
+        events.push(VoiceEvent::On { voice: vx, note, velocity });
+        events
+    }
+
+    /// Release a note. If the note is on several voices, the one played
+    /// first is released.
+    fn poly_note_off(&mut self, note: f32, ts: u64) -> Vec<VoiceEvent> {
+        let vx = self
+            .held()
+            .filter(|&vx| self.voices[vx].note == Some(note))
+            .min_by_key(|&vx| self.voices[vx].timestamp);
+        match vx {
+            Some(vx) => {
+                self.release(vx, ts);
+                vec![VoiceEvent::Off { voice: vx, note }]
+            }
+            None => vec![],
+        }
+    }
+
+    /// Make the mono voice play the note with priority among the held ones.
+    fn update_mono(&mut self, mono: Mono, ts: u64) -> Vec<VoiceEvent> {
+        let by_note = |a: &&(f32, f32), b: &&(f32, f32)| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal);
+        let target = match mono.priority {
+            Priority::Last => self.held_notes.last(),
+            Priority::Low => self.held_notes.iter().min_by(by_note),
+            Priority::High => self.held_notes.iter().max_by(by_note),
+        }
+        .cloned();
+
+        let voice = &mut self.voices[0];
+        match (voice.note, target) {
+            (None, None) => vec![],
+            (Some(current), None) => {
+                self.release(0, ts);
+                vec![VoiceEvent::Off { voice: 0, note: current }]
+            }
+            (Some(current), Some((note, _))) if current == note => vec![],
+            (Some(_), Some((note, _))) if mono.legato => {
+                voice.note = Some(note);
+                voice.timestamp = ts;
+                vec![VoiceEvent::Legato { voice: 0, note }]
+            }
+            (current, Some((note, velocity))) => {
+                voice.note = Some(note);
+                voice.velocity = velocity;
+                voice.timestamp = ts;
+                let mut events = vec![];
+                if let Some(current) = current {
+                    events.push(VoiceEvent::Off { voice: 0, note: current });
+                }
+                events.push(VoiceEvent::On { voice: 0, note, velocity });
+                events
+            }
+        }
+    }
+
+    /// Glide the voice playing `from` to another note without retriggering
+    /// it, as a sequencer slide does. The note is played normally if `from`
+    /// isn't.
+    pub fn slide(&mut self, from: f32, to: f32, velocity: f32, ts: u64) -> Vec<VoiceEvent> {
+        if let Some(mono) = self.mono {
+            match self.held_notes.iter().position(|&(n, _)| n == from) {
+                Some(i) => self.held_notes[i] = (to, velocity),
+                None => return self.note_on(to, velocity, ts),
+            }
+            return self.update_mono(Mono { legato: true, ..mono }, ts);
+        }
+        let vx = self
+            .held()
+            .filter(|&vx| self.voices[vx].note == Some(from))
+            .min_by_key(|&vx| self.voices[vx].timestamp);
+        match vx {
+            Some(vx) => {
+                let voice = &mut self.voices[vx];
+                voice.note = Some(to);
+                voice.timestamp = ts;
+                vec![VoiceEvent::Legato { voice: vx, note: to }]
+            }
+            None => self.poly_note_on(to, velocity, ts),
+        }
+    }
+
+    /// Play a note on a given voice, as MPE does, bypassing the allocation
+    /// and mono playing.
+    pub fn note_on_voice(&mut self, vx: usize, note: f32, velocity: f32, ts: u64) -> Vec<VoiceEvent> {
+        let mut events = vec![];
+        let voice = &mut self.voices[vx];
+        if let Some(replaced) = voice.note {
+            events.push(VoiceEvent::Off { voice: vx, note: replaced });
+        }
+        voice.note = Some(note);
+        voice.velocity = velocity;
+        voice.timestamp = ts;
+        events.push(VoiceEvent::On { voice: vx, note, velocity });
+        events
+    }
+
+    /// Release a given voice if it's playing the note.
+    pub fn note_off_voice(&mut self, vx: usize, note: f32, ts: u64) -> Vec<VoiceEvent> {
+        if self.voices[vx].note != Some(note) {
+            return vec![];
+        }
+        self.release(vx, ts);
+        vec![VoiceEvent::Off { voice: vx, note }]
+    }
+
+    /// Voices playing a note.
+    pub fn playing(&self, note: f32) -> Vec<usize> {
+        self.held().filter(|&vx| self.voices[vx].note == Some(note)).collect()
+    }
+
+    /// Release every held note, returning the voices and their notes.
+    pub fn release_all(&mut self, ts: u64) -> Vec<(usize, f32)> {
+        self.held_notes.clear();
+        let held: Vec<(usize, f32)> = self
+            .held()
+            .map(|vx| (vx, self.voices[vx].note.unwrap()))
+            .collect();
+        for &(vx, _) in held.iter() {
+            self.release(vx, ts);
+        }
+        held
+    }
+
+    /// Notes held for longer than `timeout` at time `now`.
+    pub fn stuck(&self, now: u64, timeout: u64) -> Vec<f32> {
+        self.held()
+            .filter(|&vx| now.saturating_sub(self.voices[vx].timestamp) > timeout)
+            .map(|vx| self.voices[vx].note.unwrap())
+            .collect()
+    }
+
+    pub fn voices(&self) -> &[Voice] {
+        &self.voices
+    }
+
+    pub fn set_allocation(&mut self, allocation: Allocation) {
+        self.allocation = allocation;
+    }
+
+    pub fn mono(&self) -> Option<Mono> {
+        self.mono
+    }
+
+    /// Switch between mono and poly playing, or change the mono settings.
+    /// Held notes should be released before switching.
+    pub fn set_mono(&mut self, mono: Option<Mono>) {
+        self.mono = mono;
+    }
+
+    pub fn unison(&self) -> Unison {
+        self.unison
+    }
+
+    /// Change the unison settings. A new voice count regroups the voices,
+    /// held notes should be released first.
+    pub fn set_unison(&mut self, mut unison: Unison) {
+        unison.voices = unison.voices.max(1).min(self.voice_count.max(1));
+        if unison.voices != self.unison.voices {
+            self.voices = vec![NONE_VOICE; self.voice_count / unison.voices];
+            self.next = 0;
+        }
+        self.unison = unison;
+    }
+
+    /// The voices making up a group.
+    pub fn group(&self, vx: usize) -> Range<usize> {
+        vx * self.unison.voices..(vx + 1) * self.unison.voices
+    }
+
+    fn release(&mut self, vx: usize, ts: u64) {
+        let voice = &mut self.voices[vx];
+        voice.note = None;
+        voice.velocity = 0.0;
+        voice.released = ts;
+    }
+
+    fn held<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
+        (0..self.voices.len()).filter(move |&vx| self.voices[vx].note.is_some())
+    }
+
+    fn free<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
+        (0..self.voices.len()).filter(move |&vx| self.voices[vx].note.is_none())
+    }
+
+    fn pick_voice(&self, note: f32) -> Option<usize> {
+        let voices = &self.voices;
+        let n = voices.len();
+        if n == 0 {
+            return None;
+        }
+        if self.allocation == Allocation::Retrigger {
+            if let Some(vx) = self.held().find(|&vx| voices[vx].note == Some(note)) {
+                return Some(vx);
+            }
+        }
+
+        let free = match self.allocation {
+            Allocation::RoundRobin => (0..n)
+                .map(|i| (self.next + i) % n)
+                .find(|&vx| voices[vx].note.is_none()),
+            Allocation::Quietest => self.free().min_by_key(|&vx| voices[vx].released),
+            _ => self.free().next(),
+        };
+        if free.is_some() {
+            return free;
+        }
+
+        let pitch = |vx: usize| voices[vx].note.unwrap();
+        let by_pitch = |a: &usize, b: &usize| pitch(*a).partial_cmp(&pitch(*b)).unwrap_or(Ordering::Equal);
+        match self.allocation {
+            Allocation::RoundRobin => Some(self.next),
+            Allocation::Oldest | Allocation::Retrigger => {
+                self.held().min_by_key(|&vx| voices[vx].timestamp)
+            }
+            Allocation::Quietest => self.held().min_by(|&a, &b| {
+                voices[a]
+                    .velocity
+                    .partial_cmp(&voices[b].velocity)
+                    .unwrap_or(Ordering::Equal)
+                    .then(voices[a].timestamp.cmp(&voices[b].timestamp))
+            }),
+            Allocation::LowestNote => self.held().max_by(by_pitch).filter(|&vx| note < pitch(vx)),
+            Allocation::HighestNote => self.held().min_by(by_pitch).filter(|&vx| note > pitch(vx)),
+        }
+    }
+}
+
 pub struct NoteModule {
-    voices: [Voices; config::CHANNEL_COUNT],
-    //note_ch_tx : mpsc::Sender::<NoteEvent>
+    voices: Vec<ChannelVoices>,
+    // MPE playing of the live channel, each member MIDI channel has a voice
+    mpe: bool,
+    // the mod wheel plays vibrato rather than setting the cutoff
+    mod_wheel_vibrato: bool,
+    // tuning tables, standard tuning first, and the one of each channel
+    tunings: Vec<Tuning>,
+    channel_tunings: Vec<usize>,
+    // voices held longer than this (ns) are assumed to have lost their
+    // note-off
+    stuck_timeout: Option<u64>,
 }
 
 impl NoteModule {
-    pub fn new() -> NoteModule {
+    pub fn new(channel_count: usize, voice_count: usize) -> NoteModule {
         NoteModule {
-            voices: [NONE_VOICES; config::CHANNEL_COUNT],
-            //note_ch_tx: note_ch_tx
+            voices: (0..channel_count)
+                .map(|_| ChannelVoices::new(voice_count, Allocation::Oldest))
+                .collect(),
+            mpe: false,
+            mod_wheel_vibrato: false,
+            tunings: vec![Tuning::standard()],
+            channel_tunings: vec![0; channel_count],
+            stuck_timeout: None,
         }
     }
 
-    pub fn note_event(&mut self, engine: &mut Engine, note_event : NoteEvent, channel : usize) {
+    /// Set up how every channel plays from the configuration.
+    pub fn configure(&mut self, engine: &mut Engine, config: &Config) {
+        let mono = match config.synth {
+            SynthMode::Poly => None,
+            SynthMode::Mono => Some(Mono {
+                priority: config.priority,
+                legato: config.legato,
+            }),
+        };
+        for channel in 0..self.voices.len() {
+            self.set_allocation(channel, config.allocation);
+            self.set_mono(engine, channel, mono, 0);
+            self.set_unison(engine, channel, config.unison(), 0);
+            engine.set_glide(channel, config.glide, 0);
+            engine.set_bend_range(channel, config.bend_range, config.mpe_bend_range, 0);
+        }
+        self.mpe = config.mpe;
+        self.mod_wheel_vibrato = config.mod_wheel_vibrato;
+    }
+
+    /// Set the tuning tables, standard tuning first, and which one each
+    /// channel plays. Channels not listed play the second table, the first
+    /// loaded one, if there is one.
+    pub fn set_tunings(&mut self, tunings: Vec<Tuning>, channel_tunings: &[usize]) {
+        let default = if tunings.len() > 1 { 1 } else { 0 };
+        for channel in 0..self.channel_tunings.len() {
+            let index = channel_tunings.get(channel).cloned().unwrap_or(default);
+            self.channel_tunings[channel] = index.min(tunings.len() - 1);
+        }
+        self.tunings = tunings;
+    }
+
+    pub fn tuning_count(&self) -> usize {
+        self.tunings.len()
+    }
 
+    /// Select the tuning table of a channel. Notes already sounding keep
+    /// their pitch.
+    pub fn set_tuning(&mut self, channel: usize, index: usize) {
+        self.channel_tunings[channel] = index.min(self.tunings.len() - 1);
+    }
+
+    /// Whether a key plays in the tuning of a channel.
+    fn tuned(&self, channel: usize, note: f32) -> bool {
+        self.tunings[self.channel_tunings[channel]].pitch(note).is_some()
+    }
+
+    pub fn mpe(&self) -> bool {
+        self.mpe
+    }
+
+    pub fn mod_wheel_vibrato(&self) -> bool {
+        self.mod_wheel_vibrato
+    }
+
+    /// The voice of the live channel an MPE member channel (1..15) plays
+    /// on, if there are enough voices.
+    pub fn mpe_voice(&self, midi_channel: usize) -> Option<usize> {
+        let slots = self.voices[0].voices().len();
+        midi_channel.checked_sub(1).filter(|&vx| vx < slots)
+    }
+
+    /// The voices of a channel playing a note, for poly aftertouch.
+    pub fn voices_playing(&self, channel: usize, note: f32) -> Vec<Range<usize>> {
+        let cv = &self.voices[channel];
+        cv.playing(note).into_iter().map(|vx| cv.group(vx)).collect()
+    }
+
+    /// The voices of an MPE member channel.
+    pub fn mpe_voices(&self, midi_channel: usize) -> Option<Range<usize>> {
+        self.mpe_voice(midi_channel).map(|vx| self.voices[0].group(vx))
+    }
+
+    /// Play or release a note from an MPE member channel on the live
+    /// channel.
+    pub fn mpe_note_event(&mut self, engine: &mut Engine, note_event: NoteEvent, midi_channel: usize) {
+        let vx = match self.mpe_voice(midi_channel) {
+            Some(vx) => vx,
+            None => return,
+        };
+        if note_event.down && !self.tuned(0, note_event.note) {
+            return;
+        }
+        let cv = &mut self.voices[0];
+        let events = if note_event.down {
+            cv.note_on_voice(vx, note_event.note, note_event.velocity, note_event.timestamp)
+        } else {
+            cv.note_off_voice(vx, note_event.note, note_event.timestamp)
+        };
+        self.send_events(engine, 0, events, note_event.velocity, note_event.timestamp);
+    }
+
+    /// Release notes held longer than `timeout_ns`, see `release_stuck`.
+    pub fn set_stuck_timeout(&mut self, timeout_ns: Option<u64>) {
+        self.stuck_timeout = timeout_ns;
+    }
+
+    pub fn set_allocation(&mut self, channel: usize, allocation: Allocation) {
+        self.voices[channel].set_allocation(allocation);
+    }
+
+    pub fn get_mono(&self, channel: usize) -> Option<Mono> {
+        self.voices[channel].mono()
+    }
+
+    pub fn get_unison(&self, channel: usize) -> Unison {
+        self.voices[channel].unison()
+    }
+
+    /// Set how many voices play each note of a channel and how they are
+    /// spread. Changing the count releases the channel's notes.
+    pub fn set_unison(&mut self, engine: &mut Engine, channel: usize, unison: Unison, ts: u64) {
+        if unison.voices != self.voices[channel].unison().voices {
+            self.all_notes_off(engine, channel, ts);
+        }
+        self.voices[channel].set_unison(unison);
+    }
+
+    /// Switch a channel between mono and poly playing, releasing its notes,
+    /// or change its mono settings.
+    pub fn set_mono(&mut self, engine: &mut Engine, channel: usize, mono: Option<Mono>, ts: u64) {
+        if self.voices[channel].mono().is_some() != mono.is_some() {
+            self.all_notes_off(engine, channel, ts);
+        }
+        self.voices[channel].set_mono(mono);
+    }
+
+    pub fn note_event(&mut self, engine: &mut Engine, note_event : NoteEvent, channel : usize) {
         let midi_num = note_event.note;
         let velocity = note_event.velocity;
-        let on = note_event.down;
         let ts = note_event.timestamp;
 
-        let targets = engine.get_control_map(channel).note_receivers.clone();
-        let mut vx = 0;
-        let mut curr_voice : Option<usize> = None;
-        let mut oldest_voice = 0;
-        let mut oldest_ts = u64::max_value();
-
-        for voice in self.voices[channel].iter_mut () {
-            //println!("{},{}", voice.note.unwrap_or(0.0), voice.timestamp);
-            if voice.timestamp < oldest_ts {
-                oldest_ts = voice.timestamp;
-                oldest_voice = vx;
-            }
-            if !on{
-                if voice.note.unwrap_or(-1.0) == midi_num{
-                    voice.note = None;
-                    voice.velocity = 0.0;
-                    curr_voice = Some(vx);
-                    break;
+        if note_event.down && !self.tuned(channel, midi_num) {
+            return;
+        }
+        let events = if note_event.down {
+            self.voices[channel].note_on(midi_num, velocity, ts)
+        } else {
+            self.voices[channel].note_off(midi_num, ts)
+        };
+        self.send_events(engine, channel, events, velocity, ts);
+    }
+
+    /// Slide from a sounding note to the note of `note_event`, see
+    /// `ChannelVoices::slide`.
+    pub fn slide(&mut self, engine: &mut Engine, channel: usize, from: f32, note_event: NoteEvent) {
+        let ts = note_event.timestamp;
+        if !self.tuned(channel, note_event.note) {
+            let events = self.voices[channel].note_off(from, ts);
+            self.send_events(engine, channel, events, 0.0, ts);
+            return;
+        }
+        let events = self.voices[channel].slide(from, note_event.note, note_event.velocity, ts);
+        self.send_events(engine, channel, events, note_event.velocity, ts);
+    }
+
+    fn send_events(&self, engine: &mut Engine, channel: usize, events: Vec<VoiceEvent>, velocity: f32, ts: u64) {
+        let control_map = engine.get_control_map(channel);
+        let cv = &self.voices[channel];
+        let unison = cv.unison();
+        let mpe = self.mpe && channel == 0;
+        // voices track keys, the engine gets tuned pitches
+        let tuning = &self.tunings[self.channel_tunings[channel]];
+        let pitch = |note: f32| tuning.pitch(note).unwrap_or(note);
+        for event in events {
+            match event {
+                VoiceEvent::On { voice, note, velocity } => {
+                    for (i, v) in cv.group(voice).enumerate() {
+                        let offset = unison.offset(i);
+                        engine.set_voice_spread(channel, v, offset * unison.detune, offset * unison.spread, ts);
+                    }
+                    if !mpe {
+                        // left over from poly aftertouch on the last note,
+                        // MPE sets it up before the note
+                        engine.set_voice_expression(channel, cv.group(voice), VoiceExpression::Pressure, 0.0, ts);
+                    }
+                    let targets = group_receivers(&control_map.note_receivers, cv.group(voice));
+                    send_note(engine, &targets, pitch(note), velocity, true, ts);
+                }
+                VoiceEvent::Off { voice, note } => {
+                    let targets = group_receivers(&control_map.note_receivers, cv.group(voice));
+                    send_note(engine, &targets, pitch(note), velocity, false, ts);
+                }
+                VoiceEvent::Legato { voice, note } => {
+                    let targets = &control_map.pitch_receivers[cv.group(voice)];
+                    send_note(engine, targets, pitch(note), velocity, true, ts);
                 }
-            }else if voice.note == None {
-                voice.note = Some(midi_num);
-                voice.velocity = velocity;
-                voice.timestamp = ts;
-                curr_voice = Some(vx);
-                break;
             }
-            vx += 1;
         }
+    }
+
+    /// Release every sounding voice of a channel.
+    pub fn all_notes_off(&mut self, engine: &mut Engine, channel : usize, ts: u64) {
+        let receivers = engine.get_control_map(channel).note_receivers;
+        for (vx, midi_num) in self.voices[channel].release_all(ts) {
+            let targets = group_receivers(&receivers, self.voices[channel].group(vx));
+            send_note(engine, &targets, midi_num, 0.0, false, ts);
+        }
+    }
 
-        if curr_voice.is_none() {
-            
-            if !on {
-                return;
-            }
-            let note = Note {
-                ixs: targets[oldest_voice].clone().into_boxed_slice(),
-                midi_num: self.voices[channel][oldest_voice].note.unwrap(),
-                velocity: velocity,
-                on: false,
-                timestamp: ts,
-            };
-            
-            engine.send(Message::Note(note));
-            self.voices[channel][oldest_voice].note = Some(midi_num);
-            curr_voice = Some(oldest_voice);
-        }
-        
-        let note = Note {
-            ixs: targets[curr_voice.unwrap_or(0)].clone().into_boxed_slice(),
-            midi_num: midi_num,
-            velocity: velocity,
-            on: on,
-            timestamp: ts,
+    /// Silence a channel at once, without waiting for the releases.
+    pub fn all_sound_off(&mut self, engine: &mut Engine, channel : usize, ts: u64) {
+        self.all_notes_off(engine, channel, ts);
+        engine.kill_voices(channel);
+    }
+
+    /// Silence everything, including the effect tails.
+    pub fn panic(&mut self, engine: &mut Engine, ts: u64) {
+        for channel in 0..self.voices.len() {
+            self.all_sound_off(engine, channel, ts);
+        }
+        engine.clear_fx();
+    }
+
+    /// Release voices of the live channel whose note-off never arrived, if
+    /// a timeout is set. The sequencer channels hold notes as long as their
+    /// steps say. `now` is the current worker timestamp.
+    pub fn release_stuck(&mut self, engine: &mut Engine, now: u64) {
+        let timeout = match self.stuck_timeout {
+            Some(timeout) => timeout,
+            None => return,
         };
-        engine.send(Message::Note(note));
-        //self.note_ch_tx.send(note_event).unwrap_or_else(|err| println!("{:?}", err));
+        for note in self.voices[0].stuck(now, timeout) {
+            warn!("releasing stuck note {} on the live channel", note);
+            let off = NoteEvent{down: false, note: note, velocity: 0.0, timestamp: now};
+            self.note_event(engine, off, 0);
+        }
     }
 
+    pub fn channel_count(&self) -> usize {
+        self.voices.len()
+    }
 
-    pub fn get_voices(&self, channel : usize) -> &Voices{
-        &self.voices[channel]
+    pub fn get_voices(&self, channel : usize) -> &[Voice] {
+        self.voices[channel].voices()
     }
 
 }
 
+fn group_receivers(receivers: &[Vec<usize>], group: Range<usize>) -> Vec<usize> {
+    receivers[group].concat()
+}
+
+fn send_note(engine: &Engine, targets: &[usize], midi_num: f32, velocity: f32, on: bool, ts: u64) {
+    let note = Note {
+        ixs: targets.to_vec().into_boxed_slice(),
+        midi_num: midi_num,
+        velocity: velocity,
+        on: on,
+        timestamp: ts,
+    };
+    engine.send(Message::Note(note));
+}
+
 #[derive(Clone)]
 pub struct NoteEvent {
     pub down: bool,
@@ -99,13 +719,300 @@ pub struct NoteEvent {
 
 pub const NONE_NOTE : NoteEvent = NoteEvent{down: false, note: 0.0, velocity: 0.0, timestamp: 0};
 
-type Voices = [Voice; config::VOICE_COUNT];
-const NONE_VOICE : Voice = Voice{note: None, velocity: 0.0, timestamp: 0};
-const NONE_VOICES : [Voice; config::VOICE_COUNT] = [NONE_VOICE; config::VOICE_COUNT];
+const NONE_VOICE : Voice = Voice{note: None, velocity: 0.0, timestamp: 0, released: 0};
 
 #[derive(Clone)]
 pub struct Voice {
     pub note : Option<f32>,
     pub velocity: f32,
+    // when the current note started
     pub timestamp : u64,
+    // when the voice was last released
+    pub released: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The voice a note-on went to, and the note it replaced.
+    fn on(cv: &mut ChannelVoices, note: f32, ts: u64) -> Option<(usize, Option<f32>)> {
+        let mut replaced = None;
+        for event in cv.note_on(note, 100.0, ts) {
+            match event {
+                VoiceEvent::Off { note, .. } => replaced = Some(note),
+                VoiceEvent::On { voice, .. } => return Some((voice, replaced)),
+                VoiceEvent::Legato { .. } => panic!("legato on a poly channel"),
+            }
+        }
+        None
+    }
+
+    /// The voice a note-off released.
+    fn off(cv: &mut ChannelVoices, note: f32, ts: u64) -> Option<usize> {
+        match cv.note_off(note, ts).as_slice() {
+            [] => None,
+            [VoiceEvent::Off { voice, .. }] => Some(*voice),
+            events => panic!("unexpected events {:?}", events),
+        }
+    }
+
+    fn mono(priority: Priority, legato: bool) -> ChannelVoices {
+        let mut cv = ChannelVoices::new(4, Allocation::Oldest);
+        cv.set_mono(Some(Mono { priority, legato }));
+        cv
+    }
+
+    fn note_on(voice: usize, note: f32) -> VoiceEvent {
+        VoiceEvent::On { voice, note, velocity: 100.0 }
+    }
+
+    fn note_off(voice: usize, note: f32) -> VoiceEvent {
+        VoiceEvent::Off { voice, note }
+    }
+
+    #[test]
+    fn free_voices_are_used_before_stealing() {
+        let mut cv = ChannelVoices::new(3, Allocation::Oldest);
+        assert_eq!(on(&mut cv, 60.0, 1), Some((0, None)));
+        assert_eq!(on(&mut cv, 62.0, 2), Some((1, None)));
+        assert_eq!(on(&mut cv, 64.0, 3), Some((2, None)));
+        assert_eq!(on(&mut cv, 65.0, 4), Some((0, Some(60.0))));
+    }
+
+    #[test]
+    fn oldest_updates_timestamp_on_steal() {
+        let mut cv = ChannelVoices::new(2, Allocation::Oldest);
+        on(&mut cv, 60.0, 1);
+        on(&mut cv, 62.0, 2);
+        assert_eq!(on(&mut cv, 64.0, 3), Some((0, Some(60.0))));
+        // voice 0 now holds the newest note, so voice 1 goes next
+        assert_eq!(on(&mut cv, 65.0, 4), Some((1, Some(62.0))));
+        assert_eq!(on(&mut cv, 67.0, 5), Some((0, Some(64.0))));
+    }
+
+    #[test]
+    fn note_off_frees_the_voice() {
+        let mut cv = ChannelVoices::new(2, Allocation::Oldest);
+        on(&mut cv, 60.0, 1);
+        on(&mut cv, 62.0, 2);
+        assert_eq!(off(&mut cv, 60.0, 3), Some(0));
+        assert_eq!(off(&mut cv, 60.0, 4), None);
+        assert_eq!(on(&mut cv, 64.0, 5), Some((0, None)));
+    }
+
+    #[test]
+    fn note_off_releases_first_of_duplicates() {
+        let mut cv = ChannelVoices::new(3, Allocation::Oldest);
+        on(&mut cv, 60.0, 1);
+        on(&mut cv, 60.0, 2);
+        assert_eq!(off(&mut cv, 60.0, 3), Some(0));
+        assert_eq!(off(&mut cv, 60.0, 4), Some(1));
+    }
+
+    #[test]
+    fn round_robin_cycles_through_free_voices() {
+        let mut cv = ChannelVoices::new(3, Allocation::RoundRobin);
+        assert_eq!(on(&mut cv, 60.0, 1), Some((0, None)));
+        off(&mut cv, 60.0, 2);
+        assert_eq!(on(&mut cv, 62.0, 3), Some((1, None)));
+        off(&mut cv, 62.0, 4);
+        assert_eq!(on(&mut cv, 64.0, 5), Some((2, None)));
+        off(&mut cv, 64.0, 6);
+        assert_eq!(on(&mut cv, 65.0, 7), Some((0, None)));
+    }
+
+    #[test]
+    fn round_robin_skips_held_voices() {
+        let mut cv = ChannelVoices::new(3, Allocation::RoundRobin);
+        on(&mut cv, 60.0, 1);
+        on(&mut cv, 62.0, 2);
+        off(&mut cv, 60.0, 3);
+        assert_eq!(on(&mut cv, 64.0, 4), Some((2, None)));
+        assert_eq!(on(&mut cv, 65.0, 5), Some((0, None)));
+        assert_eq!(on(&mut cv, 67.0, 6), Some((1, Some(62.0))));
+    }
+
+    #[test]
+    fn quietest_prefers_voice_released_longest_ago() {
+        let mut cv = ChannelVoices::new(3, Allocation::Quietest);
+        on(&mut cv, 60.0, 1);
+        on(&mut cv, 62.0, 2);
+        on(&mut cv, 64.0, 3);
+        off(&mut cv, 62.0, 10);
+        off(&mut cv, 60.0, 20);
+        assert_eq!(on(&mut cv, 65.0, 30), Some((1, None)));
+    }
+
+    #[test]
+    fn quietest_steals_softest_note() {
+        let mut cv = ChannelVoices::new(2, Allocation::Quietest);
+        cv.note_on(60.0, 100.0, 1);
+        cv.note_on(62.0, 20.0, 2);
+        assert_eq!(
+            cv.note_on(64.0, 90.0, 3),
+            vec![note_off(1, 62.0), VoiceEvent::On { voice: 1, note: 64.0, velocity: 90.0 }]
+        );
+    }
+
+    #[test]
+    fn retrigger_reuses_voice_of_same_note() {
+        let mut cv = ChannelVoices::new(3, Allocation::Retrigger);
+        on(&mut cv, 60.0, 1);
+        on(&mut cv, 62.0, 2);
+        assert_eq!(on(&mut cv, 60.0, 3), Some((0, Some(60.0))));
+        assert_eq!(on(&mut cv, 64.0, 4), Some((2, None)));
+    }
+
+    #[test]
+    fn lowest_note_priority() {
+        let mut cv = ChannelVoices::new(2, Allocation::LowestNote);
+        on(&mut cv, 60.0, 1);
+        on(&mut cv, 64.0, 2);
+        // above everything held, not played
+        assert_eq!(on(&mut cv, 67.0, 3), None);
+        // below the highest held note, which is stolen
+        assert_eq!(on(&mut cv, 62.0, 4), Some((1, Some(64.0))));
+    }
+
+    #[test]
+    fn highest_note_priority() {
+        let mut cv = ChannelVoices::new(2, Allocation::HighestNote);
+        on(&mut cv, 60.0, 1);
+        on(&mut cv, 64.0, 2);
+        assert_eq!(on(&mut cv, 55.0, 3), None);
+        assert_eq!(on(&mut cv, 62.0, 4), Some((0, Some(60.0))));
+    }
+
+    #[test]
+    fn release_all_returns_held_notes() {
+        let mut cv = ChannelVoices::new(3, Allocation::Oldest);
+        on(&mut cv, 60.0, 1);
+        on(&mut cv, 62.0, 2);
+        assert_eq!(cv.release_all(3), vec![(0, 60.0), (1, 62.0)]);
+        assert!(cv.voices().iter().all(|voice| voice.note.is_none()));
+        assert_eq!(cv.release_all(4), vec![]);
+    }
+
+    #[test]
+    fn stuck_notes_are_found_by_age() {
+        let mut cv = ChannelVoices::new(2, Allocation::Oldest);
+        on(&mut cv, 60.0, 0);
+        on(&mut cv, 62.0, 50);
+        assert_eq!(cv.stuck(100, 60), vec![60.0]);
+        assert_eq!(cv.stuck(100, 200), vec![]);
+    }
+
+    #[test]
+    fn mono_last_note_priority() {
+        let mut cv = mono(Priority::Last, false);
+        assert_eq!(cv.note_on(60.0, 100.0, 1), vec![note_on(0, 60.0)]);
+        assert_eq!(cv.note_on(64.0, 100.0, 2), vec![note_off(0, 60.0), note_on(0, 64.0)]);
+        // back to the note still held
+        assert_eq!(cv.note_off(64.0, 3), vec![note_off(0, 64.0), note_on(0, 60.0)]);
+        assert_eq!(cv.note_off(60.0, 4), vec![note_off(0, 60.0)]);
+        assert_eq!(cv.note_off(60.0, 5), vec![]);
+    }
+
+    #[test]
+    fn mono_low_note_priority() {
+        let mut cv = mono(Priority::Low, false);
+        cv.note_on(60.0, 100.0, 1);
+        assert_eq!(cv.note_on(64.0, 100.0, 2), vec![]);
+        assert_eq!(cv.note_on(55.0, 100.0, 3), vec![note_off(0, 60.0), note_on(0, 55.0)]);
+        assert_eq!(cv.note_off(64.0, 4), vec![]);
+        assert_eq!(cv.note_off(55.0, 5), vec![note_off(0, 55.0), note_on(0, 60.0)]);
+    }
+
+    #[test]
+    fn mono_high_note_priority() {
+        let mut cv = mono(Priority::High, false);
+        cv.note_on(60.0, 100.0, 1);
+        assert_eq!(cv.note_on(55.0, 100.0, 2), vec![]);
+        assert_eq!(cv.note_on(64.0, 100.0, 3), vec![note_off(0, 60.0), note_on(0, 64.0)]);
+    }
+
+    #[test]
+    fn mono_legato_only_changes_pitch() {
+        let mut cv = mono(Priority::Last, true);
+        assert_eq!(cv.note_on(60.0, 100.0, 1), vec![note_on(0, 60.0)]);
+        assert_eq!(cv.note_on(64.0, 100.0, 2), vec![VoiceEvent::Legato { voice: 0, note: 64.0 }]);
+        assert_eq!(cv.note_off(64.0, 3), vec![VoiceEvent::Legato { voice: 0, note: 60.0 }]);
+        assert_eq!(cv.note_off(60.0, 4), vec![note_off(0, 60.0)]);
+        // detached notes retrigger
+        assert_eq!(cv.note_on(62.0, 100.0, 5), vec![note_on(0, 62.0)]);
+    }
+
+    #[test]
+    fn slide_changes_pitch_without_retrigger() {
+        let mut cv = ChannelVoices::new(2, Allocation::Oldest);
+        cv.note_on(60.0, 100.0, 1);
+        cv.note_on(64.0, 100.0, 2);
+        assert_eq!(cv.slide(64.0, 67.0, 100.0, 3), vec![VoiceEvent::Legato { voice: 1, note: 67.0 }]);
+        assert_eq!(off(&mut cv, 67.0, 4), Some(1));
+        // nothing to slide from
+        assert_eq!(cv.slide(64.0, 65.0, 100.0, 5), vec![note_on(1, 65.0)]);
+
+        let mut cv = mono(Priority::Last, false);
+        cv.note_on(60.0, 100.0, 1);
+        assert_eq!(cv.slide(60.0, 62.0, 100.0, 2), vec![VoiceEvent::Legato { voice: 0, note: 62.0 }]);
+        assert_eq!(cv.note_off(62.0, 3), vec![note_off(0, 62.0)]);
+    }
+
+    #[test]
+    fn mono_release_all_forgets_held_notes() {
+        let mut cv = mono(Priority::Last, false);
+        cv.note_on(60.0, 100.0, 1);
+        cv.note_on(64.0, 100.0, 2);
+        assert_eq!(cv.release_all(3), vec![(0, 64.0)]);
+        assert_eq!(cv.note_off(64.0, 4), vec![]);
+        assert_eq!(cv.note_on(67.0, 100.0, 5), vec![note_on(0, 67.0)]);
+    }
+
+    #[test]
+    fn unison_divides_the_voices_into_groups() {
+        let mut cv = ChannelVoices::new(8, Allocation::Oldest);
+        cv.set_unison(Unison { voices: 3, detune: 0.1, spread: 1.0 });
+        assert_eq!(cv.voices().len(), 2);
+        assert_eq!(on(&mut cv, 60.0, 1), Some((0, None)));
+        assert_eq!(on(&mut cv, 62.0, 2), Some((1, None)));
+        assert_eq!(on(&mut cv, 64.0, 3), Some((0, Some(60.0))));
+        assert_eq!(cv.group(1), 3..6);
+    }
+
+    #[test]
+    fn unison_is_limited_to_the_voice_count() {
+        let mut cv = ChannelVoices::new(4, Allocation::Oldest);
+        cv.set_unison(Unison { voices: 16, ..Unison::OFF });
+        assert_eq!(cv.unison().voices, 4);
+        assert_eq!(cv.voices().len(), 1);
+        assert_eq!(cv.group(0), 0..4);
+    }
+
+    #[test]
+    fn unison_offsets_span_both_sides() {
+        let unison = Unison { voices: 5, ..Unison::OFF };
+        let offsets: Vec<f32> = (0..5).map(|i| unison.offset(i)).collect();
+        assert_eq!(offsets, vec![-1.0, -0.5, 0.0, 0.5, 1.0]);
+        assert_eq!(Unison::OFF.offset(0), 0.0);
+    }
+
+    #[test]
+    fn note_on_voice_bypasses_allocation() {
+        let mut cv = ChannelVoices::new(4, Allocation::Oldest);
+        assert_eq!(cv.note_on_voice(2, 60.0, 100.0, 1), vec![note_on(2, 60.0)]);
+        assert_eq!(cv.note_on_voice(2, 62.0, 100.0, 2), vec![note_off(2, 60.0), note_on(2, 62.0)]);
+        // a stale note-off leaves the new note alone
+        assert_eq!(cv.note_off_voice(2, 60.0, 3), vec![]);
+        assert_eq!(cv.playing(62.0), vec![2]);
+        assert_eq!(cv.note_off_voice(2, 62.0, 4), vec![note_off(2, 62.0)]);
+        assert_eq!(on(&mut cv, 64.0, 5), Some((0, None)));
+    }
+
+    #[test]
+    fn allocation_from_ctrl_covers_all_modes() {
+        assert_eq!(Allocation::from_ctrl(0.0), Allocation::RoundRobin);
+        assert_eq!(Allocation::from_ctrl(0.5), Allocation::Retrigger);
+        assert_eq!(Allocation::from_ctrl(1.0), Allocation::HighestNote);
+    }
 }
@@ -0,0 +1,110 @@
+//! Project files: the tempo and the sequencer patterns, stored as TOML.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::sequencer::{Condition, Groove};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Project {
+    #[serde(default = "default_bpm")]
+    pub bpm: f32,
+    #[serde(default)]
+    pub patterns: Vec<Pattern>,
+}
+
+/// The steps of one channel's sequencer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pattern {
+    pub channel: usize,
+    pub length: usize,
+    /// Timing of the channel, when it doesn't follow the global one.
+    #[serde(default)]
+    pub swing: Option<f32>,
+    #[serde(default)]
+    pub groove: Option<Groove>,
+    #[serde(default)]
+    pub steps: Vec<Step>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Step {
+    /// Fraction of the step the notes are held for.
+    #[serde(default = "default_gate")]
+    pub gate: f32,
+    #[serde(default)]
+    pub tie: bool,
+    #[serde(default)]
+    pub slide: bool,
+    /// Early (negative) or late, in steps.
+    #[serde(default)]
+    pub nudge: f32,
+    /// Chance of playing, from 0 to 1.
+    #[serde(default = "default_probability")]
+    pub probability: f32,
+    /// "always", "fill", "!fill", "first" or "A:B" to play on loop A of
+    /// every B.
+    #[serde(default)]
+    pub condition: Condition,
+    /// Times the notes play within the step.
+    #[serde(default = "default_ratchet")]
+    pub ratchet: usize,
+    // TOML needs the tables after the values
+    /// Channel parameters overridden while the step plays, by name.
+    #[serde(default)]
+    pub locks: BTreeMap<String, f32>,
+    #[serde(default)]
+    pub notes: Vec<StepNote>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StepNote {
+    pub note: f32,
+    pub velocity: f32,
+}
+
+fn default_bpm() -> f32 {
+    120.0
+}
+
+fn default_gate() -> f32 {
+    1.0
+}
+
+fn default_probability() -> f32 {
+    1.0
+}
+
+fn default_ratchet() -> usize {
+    1
+}
+
+impl Default for Project {
+    fn default() -> Project {
+        Project {
+            bpm: default_bpm(),
+            patterns: vec![],
+        }
+    }
+}
+
+impl Project {
+    pub fn load(path: &Path) -> Result<Project, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("can't read project {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("invalid project {}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = toml::to_string(self)
+            .map_err(|e| format!("can't serialize project: {}", e))?;
+        fs::write(path, text).map_err(|e| format!("can't write project {}: {}", path.display(), e))
+    }
+
+    pub fn pattern(&self, channel: usize) -> Option<&Pattern> {
+        self.patterns.iter().find(|p| p.channel == channel)
+    }
+}
@@ -0,0 +1,138 @@
+//! Offline rendering of a project to a WAV file.
+
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use synthesizer_io_core::module::N_SAMPLES_PER_CHUNK;
+use synthesizer_io_core::worker::Worker;
+
+use crate::config::Config;
+use crate::engine::Engine;
+use crate::note::NoteModule;
+use crate::project::Project;
+use crate::sequencer::{self, STEPS_PER_BAR};
+use crate::tuning;
+
+/// Seconds rendered after the last step so releases and effects can ring out.
+const TAIL_SECONDS: f32 = 2.0;
+
+/// Render `config.bars` bars of the project, stepping the sequencers on the
+/// sample clock instead of wall time.
+pub fn render(config: &Config, project: &Project, path: &Path) -> Result<(), String> {
+    let tunings = tuning::load_tunings(config)?;
+    let sample_rate = config.sample_hz;
+    let (mut worker, tx, rx) = Worker::create(4096);
+    let mut engine = Engine::new(sample_rate, rx, tx);
+    engine.init_synth(config.synth, config.channel_count, config.voice_count);
+    engine.set_tempo(project.bpm, 0);
+    let clock = engine.get_clock();
+
+    let mut note_module = NoteModule::new(config.channel_count, config.voice_count);
+    note_module.configure(&mut engine, config);
+    note_module.set_tunings(tunings, &config.channel_tunings);
+    // the same seed every time, for the same render
+    let seed = config.seed.unwrap_or(0);
+    let mut sequencers = sequencer::create_sequencers(config, project, seed);
+
+    let half_step_ns = (30e9 / project.bpm as f64) as u64;
+    let song_end = half_step_ns * 2 * (config.bars * STEPS_PER_BAR) as u64;
+    let total_frames = (song_end as f64 * 1e-9 * sample_rate as f64
+        + (TAIL_SECONDS * sample_rate) as f64) as u64;
+
+    let mut wav = WavWriter::create(path, sample_rate as u32)?;
+    let mut frames = 0u64;
+    let swing = config.swing();
+    // steps are scheduled half a step before they're due, the first at 0
+    let mut next_step = 0;
+    let mut stopped = false;
+    while frames < total_frames {
+        let ts = clock.frames_to_ns(frames);
+        let chunk_end = clock.frames_to_ns(frames + N_SAMPLES_PER_CHUNK as u64);
+        while next_step < chunk_end + half_step_ns && next_step < song_end {
+            for sequencer in sequencers.iter_mut() {
+                sequencer.tick(&mut engine, &mut note_module, next_step, swing);
+            }
+            next_step += 2 * half_step_ns;
+        }
+        for sequencer in sequencers.iter_mut() {
+            sequencer.run_due(&mut engine, &mut note_module, chunk_end.min(song_end));
+        }
+        if !stopped && chunk_end >= song_end {
+            for sequencer in sequencers.iter_mut() {
+                sequencer.stop(&mut engine, &mut note_module, song_end);
+            }
+            stopped = true;
+        }
+
+        let bufs = worker.work(ts);
+        let left = bufs[0].get();
+        let right = bufs[bufs.len().min(2) - 1].get();
+        for i in 0..N_SAMPLES_PER_CHUNK {
+            wav.write_frame(left[i], right[i])?;
+        }
+        engine.poll_rx();
+        frames += N_SAMPLES_PER_CHUNK as u64;
+    }
+    wav.finish()
+}
+
+/// A minimal 16 bit stereo PCM WAV writer.
+struct WavWriter {
+    file: BufWriter<File>,
+    frames: u32,
+}
+
+impl WavWriter {
+    fn create(path: &Path, sample_rate: u32) -> Result<WavWriter, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("can't create {}: {}", path.display(), e))?;
+        let mut wav = WavWriter {
+            file: BufWriter::new(file),
+            frames: 0,
+        };
+        wav.write_header(sample_rate).map_err(|e| e.to_string())?;
+        Ok(wav)
+    }
+
+    fn write_header(&mut self, sample_rate: u32) -> std::io::Result<()> {
+        let channels = 2u16;
+        let bits = 16u16;
+        let block_align = channels * bits / 8;
+        let f = &mut self.file;
+        f.write_all(b"RIFF")?;
+        f.write_all(&0u32.to_le_bytes())?;
+        f.write_all(b"WAVEfmt ")?;
+        f.write_all(&16u32.to_le_bytes())?;
+        f.write_all(&1u16.to_le_bytes())?;
+        f.write_all(&channels.to_le_bytes())?;
+        f.write_all(&sample_rate.to_le_bytes())?;
+        f.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        f.write_all(&block_align.to_le_bytes())?;
+        f.write_all(&bits.to_le_bytes())?;
+        f.write_all(b"data")?;
+        f.write_all(&0u32.to_le_bytes())
+    }
+
+    fn write_frame(&mut self, left: f32, right: f32) -> Result<(), String> {
+        for sample in [left, right].iter() {
+            let value = (sample.max(-1.0).min(1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&value.to_le_bytes()).map_err(|e| e.to_string())?;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Fill in the chunk sizes now that the length is known.
+    fn finish(mut self) -> Result<(), String> {
+        let data_len = self.frames * 4;
+        let result: std::io::Result<()> = (|| {
+            self.file.seek(SeekFrom::Start(4))?;
+            self.file.write_all(&(36 + data_len).to_le_bytes())?;
+            self.file.seek(SeekFrom::Start(40))?;
+            self.file.write_all(&data_len.to_le_bytes())?;
+            self.file.flush()
+        })();
+        result.map_err(|e| e.to_string())
+    }
+}
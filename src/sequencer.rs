@@ -1,105 +1,1101 @@
-use crate::config;
-use crate::engine::Engine;
+use crate::config::Config;
+use crate::engine::{Engine, Param};
 use crate::note::{NoteEvent, NoteModule, NONE_NOTE};
+use crate::project::{Pattern, Project, Step, StepNote};
+use crate::rng::Rng;
+use serde::{Deserialize, Serialize};
 use synthesizer_io_core::graph::Message;
 
-const NONE_NOTES: [NoteEvent; config::VOICE_COUNT] = [NONE_NOTE; config::VOICE_COUNT];
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Range;
 
-type Notes = [NoteEvent; config::VOICE_COUNT];
+/// How real-time recording changes the steps passing by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordMode {
+    /// Add the held notes to the steps.
+    Overdub,
+    /// Replace the notes of the steps with the held notes, clearing them
+    /// when nothing is held. The other step settings are kept.
+    Replace,
+    /// Remove the held notes from the steps.
+    Erase,
+}
+
+impl RecordMode {
+    pub const COUNT: usize = 3;
+
+    pub fn from_index(index: usize) -> RecordMode {
+        match index {
+            0 => RecordMode::Overdub,
+            1 => RecordMode::Replace,
+            _ => RecordMode::Erase,
+        }
+    }
+}
+
+/// A timing template: how late each step of a repeating cycle plays, in
+/// steps, at full swing.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Groove {
+    /// Every other step late, by up to half a step.
+    Swing,
+    /// Every other step late, by up to a triplet.
+    Shuffle,
+    /// Every other step early, pushing the beat.
+    Push,
+}
+
+impl Groove {
+    pub const COUNT: usize = 3;
+
+    pub fn from_index(index: usize) -> Groove {
+        match index {
+            0 => Groove::Swing,
+            1 => Groove::Shuffle,
+            _ => Groove::Push,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Groove> {
+        match name {
+            "swing" => Some(Groove::Swing),
+            "shuffle" => Some(Groove::Shuffle),
+            "push" => Some(Groove::Push),
+            _ => None,
+        }
+    }
+
+    fn offsets(&self) -> &'static [f32] {
+        match self {
+            Groove::Swing => &[0.0, 0.5],
+            Groove::Shuffle => &[0.0, 1.0 / 3.0],
+            Groove::Push => &[0.0, -0.25],
+        }
+    }
+}
+
+/// A groove and how much of it is applied, from 0 (straight) to 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Swing {
+    pub groove: Groove,
+    pub amount: f32,
+}
+
+impl Swing {
+    pub const STRAIGHT: Swing = Swing { groove: Groove::Swing, amount: 0.0 };
+
+    /// How late a step plays, in steps.
+    pub fn offset(&self, step: usize) -> f32 {
+        let offsets = self.groove.offsets();
+        offsets[step % offsets.len()] * self.amount
+    }
+}
+
+/// How far a step can be moved off the grid, in steps. Steps are scheduled
+/// this far ahead.
+pub const MAX_OFFSET: f32 = 0.5;
+
+/// When a step plays, from the loops of the sequence and the fill mode.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Condition {
+    Always,
+    /// Only while fill is on.
+    Fill,
+    NotFill,
+    /// Only the first time through the sequence after starting.
+    First,
+    /// On loop `nth` of every `of` loops, counting from 1: "1:2" plays the
+    /// first loop and every other one after it.
+    Loop { nth: usize, of: usize },
+}
+
+impl Condition {
+    /// The conditions picked by a controller, in order.
+    pub const PRESETS: [Condition; 10] = [
+        Condition::Always,
+        Condition::Fill,
+        Condition::NotFill,
+        Condition::First,
+        Condition::Loop { nth: 1, of: 2 },
+        Condition::Loop { nth: 2, of: 2 },
+        Condition::Loop { nth: 1, of: 4 },
+        Condition::Loop { nth: 2, of: 4 },
+        Condition::Loop { nth: 3, of: 4 },
+        Condition::Loop { nth: 4, of: 4 },
+    ];
+
+    /// Parse "always", "fill", "!fill", "first" or "A:B".
+    pub fn from_name(name: &str) -> Option<Condition> {
+        match name {
+            "always" => Some(Condition::Always),
+            "fill" => Some(Condition::Fill),
+            "!fill" => Some(Condition::NotFill),
+            "first" => Some(Condition::First),
+            _ => {
+                let mut parts = name.splitn(2, ':');
+                let nth: usize = parts.next()?.parse().ok()?;
+                let of: usize = parts.next()?.parse().ok()?;
+                if nth == 0 || nth > of {
+                    return None;
+                }
+                Some(Condition::Loop { nth, of })
+            }
+        }
+    }
+}
+
+impl Default for Condition {
+    fn default() -> Condition {
+        Condition::Always
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Always => write!(f, "always"),
+            Condition::Fill => write!(f, "fill"),
+            Condition::NotFill => write!(f, "!fill"),
+            Condition::First => write!(f, "first"),
+            Condition::Loop { nth, of } => write!(f, "{}:{}", nth, of),
+        }
+    }
+}
+
+impl TryFrom<String> for Condition {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Condition, String> {
+        Condition::from_name(&name).ok_or_else(|| format!("invalid trig condition \"{}\"", name))
+    }
+}
+
+impl From<Condition> for String {
+    fn from(condition: Condition) -> String {
+        condition.to_string()
+    }
+}
+
+/// Most hits of a ratcheting step.
+pub const MAX_RATCHET: usize = 8;
+
+/// The notes of one step, a slot per voice. Slots not `down` are empty.
+pub type Notes = Vec<NoteEvent>;
+
+/// Shortest gate, as a fraction of a step.
+pub const MIN_GATE: f32 = 0.05;
+
+/// A step of a sequence: its notes and how long they're held.
+#[derive(Clone)]
+pub struct SequencerStep {
+    pub notes: Notes,
+    /// Fraction of the step the notes are held for.
+    pub gate: f32,
+    /// Keep the notes sounding from the step before instead of playing this
+    /// step's. Consecutive ties hold a note over several steps.
+    pub tie: bool,
+    /// Glide into the next step's notes without retriggering them.
+    pub slide: bool,
+    /// Play early or late, in steps, on top of the groove.
+    pub nudge: f32,
+    /// Chance of the step playing, from 0 to 1, when its condition holds.
+    pub probability: f32,
+    pub condition: Condition,
+    /// Times the notes play within the step, evenly spaced.
+    pub ratchet: usize,
+    /// Channel parameters overridden while the step plays.
+    pub locks: Vec<(Param, f32)>,
+}
+
+impl SequencerStep {
+    fn new(voice_count: usize) -> SequencerStep {
+        SequencerStep {
+            notes: vec![NONE_NOTE; voice_count],
+            gate: 1.0,
+            tie: false,
+            slide: false,
+            nudge: 0.0,
+            probability: 1.0,
+            condition: Condition::Always,
+            ratchet: 1,
+            locks: vec![],
+        }
+    }
+}
+
+/// Sequence length used for channels the project has no pattern for.
+const DEFAULT_LENGTH: usize = 8;
+
+/// Steps per bar, a step being one beat.
+pub const STEPS_PER_BAR: usize = 4;
+
+/// Create a sequencer for every channel but the live one, loading the
+/// project's patterns, rewound so the first tick plays the first step. Each
+/// gets its own random numbers from `seed`.
+pub fn create_sequencers(config: &Config, project: &Project, seed: u64) -> Vec<Sequencer> {
+    let mut seeds = Rng::new(seed);
+    (1..config.channel_count)
+        .map(|channel| {
+            let mut sequencer = Sequencer::new(
+                channel,
+                project.bpm,
+                DEFAULT_LENGTH,
+                config.max_steps,
+                config.voice_count,
+            );
+            sequencer.set_seed(seeds.next_u64());
+            if let Some(pattern) = project.pattern(channel) {
+                sequencer.load_pattern(pattern);
+            }
+            sequencer.rewind();
+            sequencer
+        })
+        .collect()
+}
 
 pub struct Sequencer {
     channel: usize,
     bpm: f32,
-    steps: [Notes; config::MAX_STEPS],
+    // timing of this channel, None follows the global one
+    swing: Option<f32>,
+    groove: Option<Groove>,
+    steps: Vec<SequencerStep>,
     current_step: usize,
-    scheduled_notes: Notes,
-    last_played_notes: Notes,
+    // worker timestamp the current step plays at, once scheduled
+    pending: Option<u64>,
+    // step whose notes are playing, and the next repeats of its ratchet
+    playing: usize,
+    hit_at: Option<u64>,
+    hits_left: usize,
+    // times through the sequence, counting from 1
+    loop_count: usize,
+    fill: bool,
+    rng: Rng,
+    // notes playing, a slot per voice
+    sounding: Notes,
+    // worker timestamp the sounding notes are released at, None while they
+    // are held into the next step
+    release_at: Option<u64>,
+    // the last step played slides into the next one
+    sliding: bool,
+    // parameters held at the values of the step playing
+    locked: Vec<Param>,
     step_size: usize,
     sequence_length: usize,
 }
 
 impl Sequencer {
-    pub fn new(channel: usize, bpm: f32, sequence_length: usize) -> Sequencer {
+    pub fn new(
+        channel: usize,
+        bpm: f32,
+        sequence_length: usize,
+        max_steps: usize,
+        voice_count: usize,
+    ) -> Sequencer {
         Sequencer {
             channel: channel,
             bpm: bpm,
-            steps: [NONE_NOTES; config::MAX_STEPS],
+            swing: None,
+            groove: None,
+            steps: vec![SequencerStep::new(voice_count); max_steps],
             current_step: 0,
-            scheduled_notes: NONE_NOTES,
-            last_played_notes: NONE_NOTES,
+            pending: None,
+            playing: 0,
+            hit_at: None,
+            hits_left: 0,
+            loop_count: 1,
+            fill: false,
+            rng: Rng::new(0),
+            sounding: vec![NONE_NOTE; voice_count],
+            release_at: None,
+            sliding: false,
+            locked: vec![],
             step_size: 1,
-            sequence_length: sequence_length,
+            sequence_length: sequence_length.min(max_steps),
+        }
+    }
+
+    /// Advance to the next step, due on the grid at timestamp `ts`, and
+    /// schedule it with the groove and its nudge. Call this `MAX_OFFSET`
+    /// steps ahead of `ts` so early steps can be honoured, and `run_due` to
+    /// send the events. `swing` is used unless the channel has its own.
+    pub fn tick(&mut self, engine: &mut Engine, note_module: &mut NoteModule, ts: u64, swing: Swing) {
+        if let Some(at) = self.pending {
+            // the step before hasn't gone out yet
+            self.run_due(engine, note_module, at);
+        }
+        self.step();
+        let offset = self.offset(self.current_step, swing) as f64 * self.step_ns();
+        self.pending = Some((ts as f64 + offset).max(0.0) as u64);
+    }
+
+    /// Send what is due by worker timestamp `until`, in order: the releases
+    /// of the sounding notes, the repeats of a ratchet and the notes of the
+    /// scheduled step. Events keep the timestamp they are due at, so with
+    /// `until` ahead of the worker, by `lookahead_ns`, they play on time
+    /// however late the control thread gets to them.
+    pub fn run_due(&mut self, engine: &mut Engine, note_module: &mut NoteModule, until: u64) {
+        loop {
+            let due = [
+                (self.release_at, Due::Release),
+                (self.hit_at, Due::Hit),
+                (self.pending, Due::Step),
+            ]
+            .iter()
+            .filter_map(|&(at, due)| at.filter(|&at| at <= until).map(|at| (at, due)))
+            // the first of equal ones
+            .min_by_key(|&(at, _)| at);
+            match due {
+                Some((at, Due::Release)) => self.release(engine, note_module, at),
+                Some((at, Due::Hit)) => {
+                    self.hit_at = None;
+                    self.repeat(engine, note_module, at);
+                }
+                Some((at, Due::Step)) => {
+                    self.pending = None;
+                    self.play(engine, note_module, at);
+                }
+                None => break,
+            }
         }
     }
 
-    pub fn update_notes(&mut self, note_module: &NoteModule) {
-        let voices = note_module.get_voices(0);
-        let mut notes = &mut self.scheduled_notes;
-        for i in 0..voices.len() {
-            if voices[i].note.is_some() {
-                notes[i].note = voices[i].note.unwrap();
-                notes[i].down = true;
-                notes[i].velocity = voices[i].velocity;
-                notes[i].timestamp = voices[i].timestamp;
+    /// Worker timestamp of the next scheduled event.
+    pub fn next_event(&self) -> Option<u64> {
+        self.pending.into_iter().chain(self.hit_at).chain(self.release_at).min()
+    }
+
+    /// How far ahead of the worker events are sent, as far as a step is
+    /// scheduled ahead of the grid.
+    pub fn lookahead_ns(&self) -> u64 {
+        (MAX_OFFSET as f64 * self.step_ns()) as u64
+    }
+
+    /// Play the current step at timestamp `ts`, if its trig condition
+    /// passes. The notes before are released, held by a tie or slid into,
+    /// and the parameter locks of the step replace the ones before.
+    fn play(&mut self, engine: &mut Engine, note_module: &mut NoteModule, ts: u64) {
+        self.playing = self.current_step;
+        self.hit_at = None;
+        let step = self.steps[self.playing].clone();
+        let holding = self.sounding.iter().any(|n| n.down);
+        let mut ratchet = 1;
+        if step.tie && holding {
+            // the notes carry on, under this step's locks
+            self.apply_locks(engine, &step.locks, ts);
+        } else if !self.trigs(&step) {
+            self.release(engine, note_module, ts);
+            self.apply_locks(engine, &[], ts);
+            self.sliding = false;
+            return;
+        } else if self.sliding && holding {
+            self.apply_locks(engine, &step.locks, ts);
+            for slot in 0..self.sounding.len() {
+                let old = self.sounding[slot].clone();
+                let mut new = step.notes[slot].clone();
+                new.timestamp = ts;
+                match (old.down, new.down) {
+                    (true, true) if old.note != new.note => {
+                        note_module.slide(engine, self.channel, old.note, new.clone());
+                    }
+                    (true, false) => {
+                        note_module.note_event(engine, off(&old, ts), self.channel);
+                    }
+                    (false, true) => note_module.note_event(engine, new.clone(), self.channel),
+                    _ => {}
+                }
+                self.sounding[slot] = new;
             }
+        } else {
+            self.release(engine, note_module, ts);
+            self.apply_locks(engine, &step.locks, ts);
+            self.trigger(engine, note_module, &step.notes, ts);
+            ratchet = step.ratchet;
         }
+        self.hits_left = ratchet - 1;
+        self.schedule(&step, ratchet, ts);
     }
 
-    pub fn tick(&mut self, engine: &mut Engine, note_module: &mut NoteModule) {
+    /// Play the notes of a ratcheting step again.
+    fn repeat(&mut self, engine: &mut Engine, note_module: &mut NoteModule, ts: u64) {
+        let step = self.steps[self.playing].clone();
+        self.hits_left = self.hits_left.saturating_sub(1);
+        self.release(engine, note_module, ts);
+        self.trigger(engine, note_module, &step.notes, ts);
+        self.schedule(&step, step.ratchet, ts);
+    }
 
-        // Send off-notes
-        for note in self.last_played_notes.iter() {
+    fn trigger(&mut self, engine: &mut Engine, note_module: &mut NoteModule, notes: &Notes, ts: u64) {
+        for (slot, note) in notes.iter().enumerate() {
             let mut note = note.clone();
-            note.down = false;
-            note.velocity = 0.0;
-            note_module.note_event(engine, note, self.channel);
+            note.timestamp = ts;
+            if note.down {
+                note_module.note_event(engine, note.clone(), self.channel);
+            }
+            self.sounding[slot] = note;
         }
-        
-        self.step();
+    }
+
+    /// Schedule the release of a hit played at `ts` from the step's gate, and
+    /// the next hit of a ratchet. Notes sliding or tied into the next step
+    /// are held.
+    fn schedule(&mut self, step: &SequencerStep, ratchet: usize, ts: u64) {
+        let hit_ns = self.step_ns() / ratchet.max(1) as f64;
+        let release_at = Some(ts + (step.gate as f64 * hit_ns) as u64);
+        if self.hits_left > 0 {
+            self.hit_at = Some(ts + hit_ns as u64);
+            self.release_at = release_at;
+            self.sliding = false;
+            return;
+        }
+        let next = self.get_next_step(self.playing);
+        let held = step.slide || self.steps[next].tie;
+        self.release_at = if held { None } else { release_at };
+        self.sliding = step.slide;
+    }
+
+    /// Whether a step's condition and probability let it play this time
+    /// round.
+    fn trigs(&mut self, step: &SequencerStep) -> bool {
+        let condition = match step.condition {
+            Condition::Always => true,
+            Condition::Fill => self.fill,
+            Condition::NotFill => !self.fill,
+            Condition::First => self.loop_count == 1,
+            Condition::Loop { nth, of } => (self.loop_count.max(1) - 1) % of.max(1) + 1 == nth,
+        };
+        condition && (step.probability >= 1.0 || self.rng.next_f32() < step.probability)
+    }
+
+    /// Send the parameter locks of a step, putting back the parameters the
+    /// step before locked and this one doesn't. The notes of the step go
+    /// out after, at the same timestamp.
+    fn apply_locks(&mut self, engine: &mut Engine, locks: &[(Param, f32)], ts: u64) {
+        for &param in self.locked.iter() {
+            if !locks.iter().any(|&(p, _)| p == param) {
+                engine.restore_param(self.channel, param, ts);
+            }
+        }
+        for &(param, value) in locks {
+            engine.lock_param(self.channel, param, value, ts);
+        }
+        self.locked = locks.iter().map(|&(p, _)| p).collect();
+    }
 
+    /// Release the notes playing, restore the locked parameters and drop the
+    /// scheduled step.
+    pub fn stop(&mut self, engine: &mut Engine, note_module: &mut NoteModule, ts: u64) {
+        // the last notes sent may still be ahead of `ts`
+        let ts = self.sounding.iter().map(|n| n.timestamp).fold(ts, u64::max);
+        self.release(engine, note_module, ts);
+        self.apply_locks(engine, &[], ts);
+        self.pending = None;
+        self.hit_at = None;
+        self.hits_left = 0;
+        self.sliding = false;
+    }
 
-        // Send current notes
-        let notes = &mut self.steps[self.current_step];
-        for i in 0..notes.len() {
-            note_module.note_event(engine, notes[i].clone(), self.channel);
-            self.last_played_notes[i].note = notes[i].note;
+    fn release(&mut self, engine: &mut Engine, note_module: &mut NoteModule, ts: u64) {
+        for note in self.sounding.iter_mut() {
+            if note.down {
+                note_module.note_event(engine, off(note, ts), self.channel);
+                note.down = false;
+            }
         }
+        self.release_at = None;
+    }
+
+    /// How far off the grid a step plays, in steps.
+    fn offset(&self, step: usize, swing: Swing) -> f32 {
+        let swing = Swing {
+            groove: self.groove.unwrap_or(swing.groove),
+            amount: self.swing.unwrap_or(swing.amount),
+        };
+        let offset = swing.offset(step) + self.steps[step].nudge;
+        offset.max(-MAX_OFFSET).min(MAX_OFFSET)
+    }
+
+    fn step_ns(&self) -> f64 {
+        60e9 / self.bpm as f64
     }
 
-    pub fn tock(&mut self, engine: &mut Engine, note_module: &mut NoteModule) {
- 
-        if self.any_scheduled_notes() {
-            let notes = &mut self.steps[self.current_step];
-            for i in 0..self.scheduled_notes.len() {
-                notes[i] = self.scheduled_notes[i].clone();
-                self.scheduled_notes[i].down = false;
+    /// Record notes, as (note, velocity) pairs, into the step playing.
+    /// Notes that don't fit in the step are dropped.
+    pub fn record(&mut self, mode: RecordMode, notes: &[(f32, f32)]) {
+        let step = self.current_step;
+        if mode == RecordMode::Replace {
+            for note in self.steps[step].notes.iter_mut() {
+                *note = NONE_NOTE;
             }
         }
+        for &(note, velocity) in notes {
+            let result = match mode {
+                RecordMode::Overdub | RecordMode::Replace => self.set_note(step, note, velocity),
+                RecordMode::Erase => self.clear_note(step, note),
+            };
+            if let Err(e) = result {
+                debug!("{}", e);
+            }
+        }
+    }
+
+    /// Go back to the start, the next tick plays the first step.
+    pub fn rewind(&mut self) {
+        self.current_step = self.sequence_length - 1;
+        self.pending = None;
+        self.loop_count = 0;
     }
 
     fn step(&mut self) {
-        self.current_step = (self.current_step + self.step_size) % self.sequence_length;
+        self.current_step = self.get_next_step(self.current_step);
+        if self.current_step == 0 {
+            self.loop_count += 1;
+        }
     }
 
-    fn get_next_step(&self) -> usize {
-        (self.current_step + self.step_size) % self.sequence_length
+    fn get_next_step(&self, step: usize) -> usize {
+        (step + self.step_size) % self.sequence_length
     }
 
     pub fn get_bpm(&self) -> f32 {
         self.bpm
     }
-    pub fn get_current_steps(&self) -> Notes {
-        self.steps[self.current_step].clone()
+
+    /// Play the steps with a fill condition instead of those without.
+    pub fn set_fill(&mut self, fill: bool) {
+        self.fill = fill;
+    }
+
+    /// Seed the random numbers of step probabilities.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// Set the swing of this channel, None to follow the global one.
+    pub fn set_swing(&mut self, amount: Option<f32>) {
+        self.swing = amount.map(|amount| amount.max(0.0).min(1.0));
     }
 
+    /// Set the groove of this channel, None to follow the global one.
+    pub fn set_groove(&mut self, groove: Option<Groove>) {
+        self.groove = groove;
+    }
+
+    pub fn get_channel(&self) -> usize {
+        self.channel
+    }
+
+    pub fn get_length(&self) -> usize {
+        self.sequence_length
+    }
+
+    /// Add a note to a step, or change its velocity if it's already there.
+    pub fn set_note(&mut self, step: usize, note: f32, velocity: f32) -> Result<(), String> {
+        let notes = &mut self.step_mut(step)?.notes;
+        let slot = notes
+            .iter()
+            .position(|n| n.down && n.note == note)
+            .or_else(|| notes.iter().position(|n| !n.down))
+            .ok_or_else(|| format!("step {} has no room for another note", step))?;
+        notes[slot] = NoteEvent { down: true, note, velocity, timestamp: 0 };
+        Ok(())
+    }
 
-    fn any_scheduled_notes (&self) -> bool {
-        let mut scheduled_notes = false;
-        for i in 0..self.scheduled_notes.len() {
-            if self.scheduled_notes[i].down {
-                scheduled_notes = true;
+    pub fn clear_note(&mut self, step: usize, note: f32) -> Result<(), String> {
+        for n in self.step_mut(step)?.notes.iter_mut().filter(|n| n.down && n.note == note) {
+            *n = NONE_NOTE;
+        }
+        Ok(())
+    }
+
+    /// Add a note to a step, or remove it if it's there.
+    pub fn toggle_note(&mut self, step: usize, note: f32, velocity: f32) -> Result<(), String> {
+        if self.step_mut(step)?.notes.iter().any(|n| n.down && n.note == note) {
+            self.clear_note(step, note)
+        } else {
+            self.set_note(step, note, velocity)
+        }
+    }
+
+    /// Remove the notes of a step and reset its settings and locks.
+    pub fn clear_step(&mut self, step: usize) -> Result<(), String> {
+        let step = self.step_mut(step)?;
+        *step = SequencerStep::new(step.notes.len());
+        Ok(())
+    }
+
+    /// Set the velocity of every note of a step.
+    pub fn set_velocity(&mut self, step: usize, velocity: f32) -> Result<(), String> {
+        for n in self.step_mut(step)?.notes.iter_mut().filter(|n| n.down) {
+            n.velocity = velocity;
+        }
+        Ok(())
+    }
+
+    /// Set the fraction of a step its notes are held for.
+    pub fn set_gate(&mut self, step: usize, gate: f32) -> Result<(), String> {
+        self.step_mut(step)?.gate = gate.max(MIN_GATE).min(1.0);
+        Ok(())
+    }
+
+    pub fn set_tie(&mut self, step: usize, tie: bool) -> Result<(), String> {
+        self.step_mut(step)?.tie = tie;
+        Ok(())
+    }
+
+    pub fn set_slide(&mut self, step: usize, slide: bool) -> Result<(), String> {
+        self.step_mut(step)?.slide = slide;
+        Ok(())
+    }
+
+    /// Set the chance of a step playing, from 0 to 1.
+    pub fn set_probability(&mut self, step: usize, probability: f32) -> Result<(), String> {
+        self.step_mut(step)?.probability = probability.max(0.0).min(1.0);
+        Ok(())
+    }
+
+    pub fn set_condition(&mut self, step: usize, condition: Condition) -> Result<(), String> {
+        self.step_mut(step)?.condition = condition;
+        Ok(())
+    }
+
+    /// Set how many times a step's notes play within the step.
+    pub fn set_ratchet(&mut self, step: usize, ratchet: usize) -> Result<(), String> {
+        self.step_mut(step)?.ratchet = ratchet.max(1).min(MAX_RATCHET);
+        Ok(())
+    }
+
+    /// Override a channel parameter while a step plays, in the units of the
+    /// parameter.
+    pub fn set_lock(&mut self, step: usize, param: Param, value: f32) -> Result<(), String> {
+        let locks = &mut self.step_mut(step)?.locks;
+        match locks.iter_mut().find(|(p, _)| *p == param) {
+            Some(lock) => lock.1 = value,
+            None => locks.push((param, value)),
+        }
+        Ok(())
+    }
+
+    pub fn clear_locks(&mut self, step: usize) -> Result<(), String> {
+        self.step_mut(step)?.locks.clear();
+        Ok(())
+    }
+
+    /// Move a step early (negative) or late, in steps.
+    pub fn set_nudge(&mut self, step: usize, nudge: f32) -> Result<(), String> {
+        self.step_mut(step)?.nudge = nudge.max(-MAX_OFFSET).min(MAX_OFFSET);
+        Ok(())
+    }
+
+    pub fn copy_steps(&self, steps: Range<usize>) -> Vec<SequencerStep> {
+        self.steps[self.step_range(steps)].to_vec()
+    }
+
+    /// Overwrite steps starting at `step`, as far as the sequence goes.
+    pub fn paste_steps(&mut self, step: usize, steps: &[SequencerStep]) -> Result<(), String> {
+        self.step_mut(step)?;
+        let end = (step + steps.len()).min(self.sequence_length);
+        for (dest, src) in self.steps[step..end].iter_mut().zip(steps.iter()) {
+            for (slot, note) in dest.notes.iter_mut().enumerate() {
+                *note = src.notes.get(slot).cloned().unwrap_or(NONE_NOTE);
+            }
+            dest.gate = src.gate;
+            dest.tie = src.tie;
+            dest.slide = src.slide;
+            dest.nudge = src.nudge;
+            dest.probability = src.probability;
+            dest.condition = src.condition;
+            dest.ratchet = src.ratchet;
+            dest.locks = src.locks.clone();
+        }
+        Ok(())
+    }
+
+    /// Rotate steps later by `offset`, or earlier if it's negative.
+    pub fn shift_steps(&mut self, steps: Range<usize>, offset: isize) {
+        let range = self.step_range(steps);
+        let len = range.len() as isize;
+        if len > 0 {
+            self.steps[range].rotate_right(offset.rem_euclid(len) as usize);
+        }
+    }
+
+    /// Transpose notes, keeping them in the MIDI note range.
+    pub fn transpose_steps(&mut self, steps: Range<usize>, semitones: f32) {
+        let range = self.step_range(steps);
+        for n in self.steps[range].iter_mut().flat_map(|s| s.notes.iter_mut()).filter(|n| n.down) {
+            n.note = (n.note + semitones).max(0.0).min(127.0);
+        }
+    }
+
+    /// Clear every step of the sequence.
+    pub fn clear(&mut self) {
+        for step in self.steps.iter_mut() {
+            *step = SequencerStep::new(step.notes.len());
+        }
+    }
+
+    fn step_mut(&mut self, step: usize) -> Result<&mut SequencerStep, String> {
+        if step >= self.sequence_length {
+            return Err(format!(
+                "step {} is past the end of the {} step sequence on channel {}",
+                step, self.sequence_length, self.channel
+            ));
+        }
+        Ok(&mut self.steps[step])
+    }
+
+    fn step_range(&self, steps: Range<usize>) -> Range<usize> {
+        let end = steps.end.min(self.sequence_length);
+        steps.start.min(end)..end
+    }
+
+    /// The steps of the sequence, for saving in a project.
+    pub fn to_pattern(&self) -> Pattern {
+        let steps = self.steps[..self.sequence_length]
+            .iter()
+            .map(|step| Step {
+                notes: step
+                    .notes
+                    .iter()
+                    .filter(|n| n.down)
+                    .map(|n| StepNote { note: n.note, velocity: n.velocity })
+                    .collect(),
+                gate: step.gate,
+                tie: step.tie,
+                slide: step.slide,
+                nudge: step.nudge,
+                probability: step.probability,
+                condition: step.condition,
+                ratchet: step.ratchet,
+                locks: step.locks.iter().map(|&(p, v)| (p.name().to_string(), v)).collect(),
+            })
+            .collect();
+        Pattern {
+            channel: self.channel,
+            length: self.sequence_length,
+            swing: self.swing,
+            groove: self.groove,
+            steps,
+        }
+    }
+
+    /// Replace the sequence with a pattern from a project. Steps and notes
+    /// that don't fit are dropped.
+    pub fn load_pattern(&mut self, pattern: &Pattern) {
+        self.sequence_length = pattern.length.max(1).min(self.steps.len());
+        self.rewind();
+        self.set_swing(pattern.swing);
+        self.groove = pattern.groove;
+        for (i, dest) in self.steps.iter_mut().enumerate() {
+            *dest = SequencerStep::new(dest.notes.len());
+            if let Some(step) = pattern.steps.get(i) {
+                for (note, step_note) in dest.notes.iter_mut().zip(step.notes.iter()) {
+                    note.down = true;
+                    note.note = step_note.note;
+                    note.velocity = step_note.velocity;
+                }
+                dest.gate = step.gate.max(MIN_GATE).min(1.0);
+                dest.tie = step.tie;
+                dest.slide = step.slide;
+                dest.nudge = step.nudge.max(-MAX_OFFSET).min(MAX_OFFSET);
+                dest.probability = step.probability.max(0.0).min(1.0);
+                dest.condition = step.condition;
+                dest.ratchet = step.ratchet.max(1).min(MAX_RATCHET);
+                for (name, &value) in step.locks.iter() {
+                    match Param::from_name(name) {
+                        Some(param) => dest.locks.push((param, value)),
+                        None => warn!("unknown parameter {} locked on channel {}", name, self.channel),
+                    }
+                }
             }
         }
-        scheduled_notes
+    }
+    pub fn get_current_steps(&self) -> Notes {
+        self.steps[self.current_step].notes.clone()
+    }
+}
+
+/// What `run_due` sends next.
+#[derive(Clone, Copy)]
+enum Due {
+    Release,
+    Hit,
+    Step,
+}
+
+fn off(note: &NoteEvent, ts: u64) -> NoteEvent {
+    NoteEvent { down: false, note: note.note, velocity: 0.0, timestamp: ts }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequencer() -> Sequencer {
+        Sequencer::new(1, 120.0, 4, 8, 2)
+    }
+
+    /// The notes of a step, in slot order.
+    fn notes(sequencer: &Sequencer, step: usize) -> Vec<(f32, f32)> {
+        sequencer.steps[step].notes.iter().filter(|n| n.down).map(|n| (n.note, n.velocity)).collect()
+    }
+
+    #[test]
+    fn set_note_fills_free_slots() {
+        let mut seq = sequencer();
+        seq.set_note(0, 60.0, 100.0).unwrap();
+        seq.set_note(0, 64.0, 90.0).unwrap();
+        seq.set_note(0, 60.0, 50.0).unwrap();
+        assert_eq!(notes(&seq, 0), vec![(60.0, 50.0), (64.0, 90.0)]);
+        assert!(seq.set_note(0, 67.0, 100.0).is_err());
+        assert!(seq.set_note(4, 60.0, 100.0).is_err());
+    }
+
+    #[test]
+    fn toggle_note_adds_and_removes() {
+        let mut seq = sequencer();
+        seq.toggle_note(1, 60.0, 100.0).unwrap();
+        assert_eq!(notes(&seq, 1), vec![(60.0, 100.0)]);
+        seq.toggle_note(1, 60.0, 100.0).unwrap();
+        assert_eq!(notes(&seq, 1), vec![]);
+    }
+
+    #[test]
+    fn set_velocity_changes_the_whole_step() {
+        let mut seq = sequencer();
+        seq.set_note(2, 60.0, 100.0).unwrap();
+        seq.set_note(2, 64.0, 90.0).unwrap();
+        seq.set_velocity(2, 40.0).unwrap();
+        assert_eq!(notes(&seq, 2), vec![(60.0, 40.0), (64.0, 40.0)]);
+    }
+
+    #[test]
+    fn copy_and_paste_stop_at_the_sequence_end() {
+        let mut seq = sequencer();
+        seq.set_note(0, 60.0, 100.0).unwrap();
+        seq.set_note(1, 62.0, 100.0).unwrap();
+        let clipboard = seq.copy_steps(0..2);
+        seq.paste_steps(3, &clipboard).unwrap();
+        assert_eq!(notes(&seq, 3), vec![(60.0, 100.0)]);
+        assert_eq!(notes(&seq, 4), vec![]);
+    }
+
+    #[test]
+    fn gate_tie_and_slide_are_kept_with_the_step() {
+        let mut seq = sequencer();
+        seq.set_note(0, 60.0, 100.0).unwrap();
+        seq.set_gate(0, 0.0).unwrap();
+        seq.set_slide(0, true).unwrap();
+        seq.set_tie(1, true).unwrap();
+        assert_eq!(seq.steps[0].gate, MIN_GATE);
+
+        let clipboard = seq.copy_steps(0..2);
+        seq.paste_steps(2, &clipboard).unwrap();
+        assert!(seq.steps[2].slide && seq.steps[3].tie);
+
+        let mut loaded = sequencer();
+        loaded.load_pattern(&seq.to_pattern());
+        assert_eq!(loaded.steps[2].gate, MIN_GATE);
+        assert!(loaded.steps[2].slide && loaded.steps[3].tie);
+
+        seq.clear_step(0).unwrap();
+        assert_eq!(seq.steps[0].gate, 1.0);
+        assert!(!seq.steps[0].slide);
+        assert_eq!(notes(&seq, 0), vec![]);
+    }
+
+    #[test]
+    fn swing_delays_every_other_step() {
+        let swing = Swing { groove: Groove::Swing, amount: 0.5 };
+        assert_eq!(swing.offset(0), 0.0);
+        assert_eq!(swing.offset(1), 0.25);
+        assert_eq!(swing.offset(3), 0.25);
+        assert_eq!(Swing::STRAIGHT.offset(1), 0.0);
+        let push = Swing { groove: Groove::Push, amount: 1.0 };
+        assert_eq!(push.offset(1), -0.25);
+    }
+
+    #[test]
+    fn channel_timing_overrides_the_global_one() {
+        let mut seq = sequencer();
+        let global = Swing { groove: Groove::Swing, amount: 1.0 };
+        assert_eq!(seq.offset(1, global), 0.5);
+        seq.set_swing(Some(0.5));
+        seq.set_groove(Some(Groove::Push));
+        assert_eq!(seq.offset(1, global), -0.125);
+        seq.set_swing(None);
+        seq.set_groove(None);
+        // nudges add up with the groove, within half a step
+        seq.set_nudge(1, -0.1).unwrap();
+        assert_eq!(seq.offset(1, global), 0.4);
+        seq.set_nudge(3, 0.3).unwrap();
+        assert_eq!(seq.offset(3, global), MAX_OFFSET);
+        seq.set_nudge(0, -2.0).unwrap();
+        assert_eq!(seq.steps[0].nudge, -MAX_OFFSET);
+
+        let mut loaded = sequencer();
+        seq.set_swing(Some(0.25));
+        loaded.load_pattern(&seq.to_pattern());
+        assert_eq!(loaded.offset(1, Swing::STRAIGHT), -0.1 + 0.125);
+    }
+
+    #[test]
+    fn condition_names_round_trip() {
+        for &condition in Condition::PRESETS.iter() {
+            assert_eq!(Condition::from_name(&condition.to_string()), Some(condition));
+        }
+        assert_eq!(Condition::from_name("3:8"), Some(Condition::Loop { nth: 3, of: 8 }));
+        assert_eq!(Condition::from_name("0:2"), None);
+        assert_eq!(Condition::from_name("3:2"), None);
+        assert_eq!(Condition::from_name("sometimes"), None);
+    }
+
+    #[test]
+    fn conditions_follow_loops_and_fill() {
+        let mut seq = sequencer();
+        let mut step = SequencerStep::new(2);
+        step.condition = Condition::Loop { nth: 2, of: 4 };
+        let plays: Vec<bool> = (1..=8)
+            .map(|n| {
+                seq.loop_count = n;
+                seq.trigs(&step)
+            })
+            .collect();
+        assert_eq!(plays, vec![false, true, false, false, false, true, false, false]);
+
+        // the first step after rewinding starts loop 1
+        seq.rewind();
+        seq.step();
+        assert_eq!((seq.current_step, seq.loop_count), (0, 1));
+        step.condition = Condition::First;
+        assert!(seq.trigs(&step));
+        for _ in 0..4 {
+            seq.step();
+        }
+        assert!(!seq.trigs(&step));
+
+        step.condition = Condition::Fill;
+        assert!(!seq.trigs(&step));
+        seq.set_fill(true);
+        assert!(seq.trigs(&step));
+        step.condition = Condition::NotFill;
+        assert!(!seq.trigs(&step));
+    }
+
+    #[test]
+    fn first_tick_plays_the_first_step() {
+        let config = Config::default();
+        let mut sequencers = create_sequencers(&config, &Project::default(), 0);
+        let seq = &mut sequencers[0];
+        seq.step();
+        assert_eq!((seq.current_step, seq.loop_count), (0, 1));
+        let mut step = SequencerStep::new(2);
+        step.condition = Condition::First;
+        assert!(seq.trigs(&step));
+    }
+
+    #[test]
+    fn probability_is_reproducible_from_the_seed() {
+        let mut step = SequencerStep::new(2);
+        step.probability = 0.5;
+        let run = |seed: u64| {
+            let mut seq = sequencer();
+            seq.set_seed(seed);
+            (0..32).map(|_| seq.trigs(&step)).collect::<Vec<bool>>()
+        };
+        let plays = run(7);
+        assert_eq!(plays, run(7));
+        assert!(plays.contains(&true) && plays.contains(&false));
+        step.probability = 0.0;
+        assert!(!sequencer().trigs(&step));
+    }
+
+    #[test]
+    fn step_settings_are_limited() {
+        let mut seq = sequencer();
+        seq.set_ratchet(0, 0).unwrap();
+        seq.set_ratchet(1, 100).unwrap();
+        seq.set_probability(2, 1.5).unwrap();
+        assert_eq!((seq.steps[0].ratchet, seq.steps[1].ratchet), (1, MAX_RATCHET));
+        assert_eq!(seq.steps[2].probability, 1.0);
+        assert!(seq.set_ratchet(4, 2).is_err());
+    }
+
+    #[test]
+    fn locks_are_kept_with_the_step() {
+        let mut seq = sequencer();
+        seq.set_lock(0, Param::Cutoff, 10.0).unwrap();
+        seq.set_lock(0, Param::Reso, 0.9).unwrap();
+        seq.set_lock(0, Param::Cutoff, 12.0).unwrap();
+        assert_eq!(seq.steps[0].locks, vec![(Param::Cutoff, 12.0), (Param::Reso, 0.9)]);
+        assert!(seq.set_lock(4, Param::Cutoff, 10.0).is_err());
+
+        let clipboard = seq.copy_steps(0..1);
+        seq.paste_steps(2, &clipboard).unwrap();
+        assert_eq!(seq.steps[2].locks, seq.steps[0].locks);
+
+        let mut pattern = seq.to_pattern();
+        pattern.steps[1].locks.insert("wobble".to_string(), 1.0);
+        let mut loaded = sequencer();
+        loaded.load_pattern(&pattern);
+        assert_eq!(loaded.steps[0].locks, vec![(Param::Cutoff, 12.0), (Param::Reso, 0.9)]);
+        assert_eq!(loaded.steps[1].locks, vec![]);
+
+        seq.clear_locks(0).unwrap();
+        seq.clear_step(2).unwrap();
+        assert_eq!(seq.steps[0].locks, vec![]);
+        assert_eq!(seq.steps[2].locks, vec![]);
+    }
+
+    #[test]
+    fn param_names_round_trip() {
+        for &param in Param::ALL.iter() {
+            assert_eq!(Param::from_name(param.name()), Some(param));
+        }
+        assert_eq!(Param::from_name("wobble"), None);
+    }
+
+    #[test]
+    fn shift_rotates_within_the_range() {
+        let mut seq = sequencer();
+        seq.set_note(0, 60.0, 100.0).unwrap();
+        seq.set_note(3, 67.0, 100.0).unwrap();
+        seq.shift_steps(0..usize::MAX, 1);
+        assert_eq!(notes(&seq, 0), vec![(67.0, 100.0)]);
+        assert_eq!(notes(&seq, 1), vec![(60.0, 100.0)]);
+        seq.shift_steps(0..2, -1);
+        assert_eq!(notes(&seq, 0), vec![(60.0, 100.0)]);
+    }
+
+    #[test]
+    fn record_modes() {
+        let mut seq = sequencer();
+        seq.set_note(0, 60.0, 100.0).unwrap();
+        seq.set_gate(0, 0.5).unwrap();
+        seq.record(RecordMode::Overdub, &[(64.0, 80.0)]);
+        assert_eq!(notes(&seq, 0), vec![(60.0, 100.0), (64.0, 80.0)]);
+        seq.record(RecordMode::Erase, &[(60.0, 0.0)]);
+        assert_eq!(notes(&seq, 0), vec![(64.0, 80.0)]);
+        seq.record(RecordMode::Replace, &[(67.0, 90.0)]);
+        assert_eq!(notes(&seq, 0), vec![(67.0, 90.0)]);
+        seq.record(RecordMode::Replace, &[]);
+        assert_eq!(notes(&seq, 0), vec![]);
+        assert_eq!(seq.steps[0].gate, 0.5);
+    }
+
+    #[test]
+    fn transpose_and_clear() {
+        let mut seq = sequencer();
+        seq.set_note(0, 60.0, 100.0).unwrap();
+        seq.set_note(1, 126.0, 100.0).unwrap();
+        seq.transpose_steps(0..2, 2.0);
+        assert_eq!(notes(&seq, 0), vec![(62.0, 100.0)]);
+        assert_eq!(notes(&seq, 1), vec![(127.0, 100.0)]);
+        seq.clear();
+        assert!((0..4).all(|step| notes(&seq, step).is_empty()));
     }
 }
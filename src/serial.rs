@@ -1,49 +1,116 @@
+use log::{debug, info};
 use serialport;
 use std::time::Duration;
 use crate::engine::{Engine, ControlMap};
+use crate::input::CtrlEvent;
 use crate::note::NoteModule;
 use std::io::{self};
 
-pub struct Serial {
+/// Size of a packet sent by the panel: command, three data bytes and a
+/// checksum of the first four.
+const PACKET_SIZE: usize = 5;
+
+/// Largest value reported for a potentiometer (12 bit ADC).
+const POT_MAX: f32 = 4095.0;
 
+pub struct Serial {
+    pending: Vec<u8>,
 }
 
 impl Serial {
     pub fn new() -> Serial {
-        Serial {}
+        Serial { pending: vec![] }
     }
-    pub fn dispatch_serial(&mut self, note_module : &mut NoteModule, engine: &mut Engine, serial_buf: &[u8], ts: u64) {
-        let mut i = 0;
-        let current_channel = engine.get_current_channel();
-        let control_map : ControlMap = engine.get_current_control_map();
-        
-        println!("{:?}",serial_buf);
+    /// Handle the panel's packets. Sequencer edits are returned for the
+    /// control thread to apply.
+    pub fn dispatch_serial(&mut self, note_module : &mut NoteModule, engine: &mut Engine, serial_buf: &[u8], ts: u64) -> Vec<CtrlEvent> {
+        self.pending.extend_from_slice(serial_buf);
+        let mut edits = vec![];
+
+        while self.pending.len() >= PACKET_SIZE {
+            let checksum = self.pending[..4].iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            if checksum != self.pending[4] {
+                // out of sync, drop a byte and try again
+                self.pending.remove(0);
+                continue;
+            }
+            let packet: Vec<u8> = self.pending.drain(..PACKET_SIZE).collect();
+            match packet[0] {
+                b'P' => {
+                    let value = (packet[2] as u16 | (packet[3] as u16) << 4) as f32 / POT_MAX;
+                    Serial::handle_pot(engine, packet[1], value.min(1.0), ts);
+                }
+                b'B' => {
+                    let down = packet[2] != 0;
+                    edits.extend(Serial::handle_button(note_module, engine, packet[1], down, ts));
+                }
+                _ => debug!("don't have handler for panel command {}", packet[0]),
+            }
+        }
+        edits
     }
-}
 
-fn main() {
-    let ports = serialport::available_ports().expect("No ports found!");
-    for p in ports {
-        println!("{}", p.port_name);
+    fn handle_button(note_module: &mut NoteModule, engine: &mut Engine, button: u8, down: bool, ts: u64) -> Vec<CtrlEvent> {
+        if !down {
+            return vec![];
+        }
+        let channel = engine.get_current_channel();
+        let step = engine.get_edit_step();
+        match button {
+            3 => {
+                info!("Panic");
+                note_module.panic(engine, ts);
+                vec![]
+            }
+            4 => vec![CtrlEvent::MoveEditStep(-1)],
+            5 => vec![CtrlEvent::MoveEditStep(1)],
+            // toggle the notes held on the keyboard at the edit step
+            6 => note_module
+                .get_voices(0)
+                .iter()
+                .filter_map(|voice| voice.note.map(|note| (note, voice.velocity)))
+                .map(|(note, velocity)| CtrlEvent::ToggleNote { channel, step, note, velocity })
+                .collect(),
+            7 => vec![CtrlEvent::ClearStep { channel, step }],
+            8 => vec![CtrlEvent::Copy { channel, steps: step..step + 1 }],
+            9 => vec![CtrlEvent::Paste { channel, step }],
+            10 => vec![CtrlEvent::ClearChannel { channel }],
+            11 => vec![CtrlEvent::TogglePlay],
+            12 => vec![CtrlEvent::ToggleRecord],
+            13 => vec![CtrlEvent::NextRecordMode],
+            _ => {
+                debug!("don't have handler for button {}", button);
+                vec![]
+            }
+        }
     }
-    let mut port = serialport::new("COM7", 115200)
-    .timeout(Duration::from_millis(10))
-    .open().expect("Failed to open port");
-
-
-    loop {
-        let mut serial_buf: Vec<u8> = vec![0; 5];
-        let result = port.read(serial_buf.as_mut_slice());
-
-        let result = match result{
-            Ok(file) => file,
-            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => 0,
-            Err(error) => {println!("{}", error); 0},
-        };
-        if result > 0 {
-            println!("{:?}",serial_buf);
+
+    fn handle_pot(engine: &mut Engine, pot: u8, value: f32, ts: u64) {
+        let control_map : ControlMap = engine.get_current_control_map();
+        match pot {
+            0 => engine.set_ctrl_const(value, 0.0, 22_000f32.log2(), control_map.cutoff, ts),
+            1 => engine.set_ctrl_const(value, 0.0, 0.995, control_map.reso, ts),
+            2 => engine.set_ctrl_const(value, 0.0, 1.0, control_map.delay_send, ts),
+            3 => engine.set_ctrl_const(value, 0.0, 1.0, control_map.reverb_send, ts),
+            _ => debug!("don't have handler for pot {}", pot),
         }
-        std::thread::sleep(Duration::from_millis(50));
     }
+}
 
+/// Open the panel's serial port.
+pub fn open_port(name: &str, baud: u32) -> Result<Box<dyn serialport::SerialPort>, String> {
+    serialport::new(name, baud)
+        .timeout(Duration::from_millis(10))
+        .open()
+        .map_err(|e| format!("can't open serial port {}: {}", name, e))
+}
+
+/// Read a chunk from the port, returning the number of bytes read. Timeouts
+/// aren't errors, they just mean the panel had nothing to say.
+pub fn read_port(port: &mut dyn serialport::SerialPort, buf: &mut [u8]) -> io::Result<usize> {
+    match port.read(buf) {
+        Ok(n) => Ok(n),
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut => Ok(0),
+        Err(e) => Err(e),
+    }
 }
@@ -0,0 +1,343 @@
+//! Tuning tables: equal temperaments and Scala scales (.scl) with keyboard
+//! mappings (.kbm). A tuning turns a key number into a pitch, given as a
+//! fractional MIDI note number so the engine keeps working in 12-TET units.
+
+use std::fs;
+use std::path::Path;
+
+use crate::config::Config;
+
+/// The tuning tables of the configuration, standard tuning first.
+pub fn load_tunings(config: &Config) -> Result<Vec<Tuning>, String> {
+    let mut tunings = vec![Tuning::standard()];
+    for spec in config.tunings.iter() {
+        let mut tuning = match spec.scale.parse::<usize>() {
+            Ok(0) => return Err("a tuning needs at least 1 step".to_string()),
+            Ok(divisions) => Tuning::equal(divisions),
+            Err(_) => Tuning::load(Path::new(&spec.scale))?,
+        };
+        if let Some(ref kbm) = spec.keymap {
+            tuning.set_keymap(Keymap::load(kbm)?);
+        }
+        tunings.push(tuning);
+    }
+    for tuning in tunings.iter_mut() {
+        tuning.set_a4(config.a4);
+    }
+    Ok(tunings)
+}
+
+/// Which scale degree each key of a repeating block plays.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    // scale degree of each key, None for keys that don't play
+    map: Vec<Option<usize>>,
+    // key playing degree 0
+    middle_note: i32,
+    // key tuned to the reference frequency
+    reference_note: i32,
+    reference_hz: f64,
+    // scale degree a block of keys spans
+    octave_degree: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tuning {
+    // cents of each degree above the root, starting with 0; the period
+    // (usually the octave) is kept separately
+    degrees: Vec<f64>,
+    period: f64,
+    // None maps the keys one to one onto the degrees
+    keymap: Option<Keymap>,
+    // reference frequency of A4 when there's no keymap, middle C (key 60)
+    // is the root and keeps its 12-TET distance to A4
+    a4: f64,
+}
+
+impl Tuning {
+    /// Standard 12 tone equal temperament.
+    pub fn standard() -> Tuning {
+        Tuning::equal(12)
+    }
+
+    /// `divisions` equal steps to the octave. Key 60 stays middle C.
+    pub fn equal(divisions: usize) -> Tuning {
+        let step = 1200.0 / divisions.max(1) as f64;
+        Tuning {
+            degrees: (0..divisions.max(1)).map(|i| i as f64 * step).collect(),
+            period: 1200.0,
+            keymap: None,
+            a4: 440.0,
+        }
+    }
+
+    /// Parse a Scala scale file.
+    pub fn from_scl(text: &str) -> Result<Tuning, String> {
+        let mut lines = text.lines().filter(|line| !line.starts_with('!'));
+        // the first line is a description
+        lines.next().ok_or("empty scale file")?;
+        let count_line = lines.next().ok_or("missing note count")?;
+        let count: usize = first_word(count_line)
+            .parse()
+            .map_err(|_| format!("invalid note count \"{}\"", count_line.trim()))?;
+        if count == 0 {
+            return Err("scale has no notes".to_string());
+        }
+        let mut cents = Vec::with_capacity(count);
+        for line in lines.filter(|line| !line.trim().is_empty()).take(count) {
+            cents.push(parse_pitch(first_word(line))?);
+        }
+        if cents.len() < count {
+            return Err(format!("scale has {} of {} notes", cents.len(), count));
+        }
+        let period = cents.pop().unwrap();
+        if !(period > 0.0) {
+            return Err("scale period must be above the root".to_string());
+        }
+        let mut degrees = vec![0.0];
+        degrees.extend(cents);
+        Ok(Tuning {
+            degrees,
+            period,
+            keymap: None,
+            a4: 440.0,
+        })
+    }
+
+    /// Load a Scala scale file.
+    pub fn load(path: &Path) -> Result<Tuning, String> {
+        Tuning::from_scl(&read(path)?).map_err(|e| format!("invalid {}: {}", path.display(), e))
+    }
+
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = Some(keymap);
+    }
+
+    /// Set the frequency of A4, used when there is no keymap setting the
+    /// reference.
+    pub fn set_a4(&mut self, hz: f64) {
+        self.a4 = hz;
+    }
+
+    /// Pitch of a key as a fractional MIDI note number, or None if the key
+    /// doesn't play. A fractional key lies between its neighbours.
+    pub fn pitch(&self, key: f32) -> Option<f32> {
+        let low = key.floor();
+        let frac = (key - low) as f64;
+        let (reference_key, reference_hz) = match self.keymap {
+            Some(ref keymap) => (keymap.reference_note, keymap.reference_hz),
+            None => (60, self.a4 * (-0.75f64).exp2()),
+        };
+        let reference = self.cents(reference_key).unwrap_or(0.0);
+        let mut cents = self.cents(low as i32)?;
+        if frac > 0.0 {
+            cents += (self.cents(low as i32 + 1)? - cents) * frac;
+        }
+        let reference_pitch = 69.0 + 12.0 * (reference_hz / 440.0).log2();
+        Some((reference_pitch + (cents - reference) / 100.0) as f32)
+    }
+
+    /// Cents of a key above the root of the scale.
+    fn cents(&self, key: i32) -> Option<f64> {
+        let n = self.degrees.len() as i64;
+        let degree = match self.keymap {
+            Some(ref keymap) => {
+                let size = keymap.map.len() as i64;
+                let offset = (key - keymap.middle_note) as i64;
+                if size == 0 {
+                    // linear mapping
+                    offset
+                } else {
+                    let block = offset.div_euclid(size);
+                    let degree = keymap.map[offset.rem_euclid(size) as usize]? as i64;
+                    // 0 means the scale's own period
+                    let octave_degree = match keymap.octave_degree {
+                        0 => n,
+                        d => d as i64,
+                    };
+                    block * octave_degree + degree
+                }
+            }
+            None => key as i64 - 60,
+        };
+        let period = degree.div_euclid(n) as f64;
+        Some(period * self.period + self.degrees[degree.rem_euclid(n) as usize])
+    }
+}
+
+impl Keymap {
+    /// Load a Scala keyboard mapping file.
+    pub fn load(path: &Path) -> Result<Keymap, String> {
+        Keymap::from_kbm(&read(path)?).map_err(|e| format!("invalid {}: {}", path.display(), e))
+    }
+
+    /// Parse a Scala keyboard mapping file.
+    pub fn from_kbm(text: &str) -> Result<Keymap, String> {
+        let mut values = text
+            .lines()
+            .filter(|line| !line.starts_with('!') && !line.trim().is_empty())
+            .map(first_word);
+        let mut next = |name: &str| values.next().ok_or(format!("missing {}", name));
+        let number = |name: &str, value: &str| {
+            value.parse::<i32>().map_err(|_| format!("invalid {} \"{}\"", name, value))
+        };
+
+        let size = number("map size", next("map size")?)?.max(0) as usize;
+        // the playable key range, all keys are played
+        next("first note")?;
+        next("last note")?;
+        let middle_note = number("middle note", next("middle note")?)?;
+        let reference_note = number("reference note", next("reference note")?)?;
+        let reference = next("reference frequency")?;
+        let reference_hz: f64 = reference
+            .parse()
+            .map_err(|_| format!("invalid reference frequency \"{}\"", reference))?;
+        let octave_degree = number("octave degree", next("octave degree")?)?.max(0) as usize;
+
+        let mut map = Vec::with_capacity(size);
+        for _ in 0..size {
+            let value = next("key mapping")?;
+            map.push(match value {
+                "x" | "X" => None,
+                _ => Some(number("key mapping", value)?.max(0) as usize),
+            });
+        }
+        if !(reference_hz > 0.0) {
+            return Err("reference frequency must be positive".to_string());
+        }
+        Ok(Keymap {
+            map,
+            middle_note,
+            reference_note,
+            reference_hz,
+            octave_degree,
+        })
+    }
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path.display(), e))
+}
+
+fn first_word(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+/// A Scala pitch: cents if it has a period, a ratio or whole number
+/// otherwise.
+fn parse_pitch(value: &str) -> Result<f64, String> {
+    let invalid = || format!("invalid pitch \"{}\"", value);
+    if value.contains('.') {
+        return value.parse().map_err(|_| invalid());
+    }
+    let mut parts = value.splitn(2, '/');
+    let num: f64 = parts.next().unwrap_or("").parse().map_err(|_| invalid())?;
+    let den: f64 = match parts.next() {
+        Some(den) => den.parse().map_err(|_| invalid())?,
+        None => 1.0,
+    };
+    if !(num > 0.0 && den > 0.0) {
+        return Err(invalid());
+    }
+    Ok(1200.0 * (num / den).log2())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: Option<f32>, expected: f32) {
+        let actual = actual.expect("key should play");
+        assert!((actual - expected).abs() < 1e-3, "{} != {}", actual, expected);
+    }
+
+    const JUST: &str = "! just.scl
+!
+Just major
+ 7
+!
+ 9/8
+ 5/4
+ 4/3
+ 3/2
+ 5/3
+ 15/8
+ 2/1
+";
+
+    #[test]
+    fn standard_tuning_is_the_identity() {
+        let tuning = Tuning::standard();
+        for &key in [0.0, 59.0, 60.0, 69.0, 127.0, 60.5].iter() {
+            assert_near(tuning.pitch(key), key);
+        }
+    }
+
+    #[test]
+    fn equal_temperaments_keep_middle_c() {
+        let tuning = Tuning::equal(24);
+        assert_near(tuning.pitch(60.0), 60.0);
+        assert_near(tuning.pitch(61.0), 60.5);
+        assert_near(tuning.pitch(84.0), 72.0);
+    }
+
+    #[test]
+    fn a4_moves_every_key() {
+        let mut tuning = Tuning::standard();
+        tuning.set_a4(432.0);
+        let offset = 12.0 * (432f32 / 440.0).log2();
+        assert_near(tuning.pitch(69.0), 69.0 + offset);
+        assert_near(tuning.pitch(60.0), 60.0 + offset);
+    }
+
+    #[test]
+    fn scala_ratios_and_cents() {
+        let tuning = Tuning::from_scl(JUST).unwrap();
+        // consecutive keys walk the scale degrees up from middle C
+        assert_near(tuning.pitch(62.0), 60.0 + 12.0 * (5f32 / 4.0).log2());
+        assert_near(tuning.pitch(64.0), 60.0 + 12.0 * (3f32 / 2.0).log2());
+        assert_near(tuning.pitch(67.0), 72.0);
+        assert_near(tuning.pitch(53.0), 48.0);
+        let cents = Tuning::from_scl("cents\n2\n150.0\n1200.0 octave\n").unwrap();
+        assert_near(cents.pitch(61.0), 61.5);
+        assert_near(cents.pitch(62.0), 72.0);
+    }
+
+    #[test]
+    fn invalid_scales_are_rejected() {
+        assert!(Tuning::from_scl("").is_err());
+        assert!(Tuning::from_scl("name\n3\n100.0\n").is_err());
+        assert!(Tuning::from_scl("name\n1\nfoo\n").is_err());
+    }
+
+    #[test]
+    fn keymap_skips_unmapped_keys() {
+        let kbm = "! white keys only
+12
+0
+127
+60
+69
+440.0
+7
+0
+x
+1
+x
+2
+3
+x
+4
+x
+5
+x
+6
+";
+        let mut tuning = Tuning::from_scl(JUST).unwrap();
+        tuning.set_keymap(Keymap::from_kbm(kbm).unwrap());
+        assert_eq!(tuning.pitch(61.0), None);
+        assert_near(tuning.pitch(69.0), 69.0);
+        // a just major third below the reference sixth
+        assert_near(tuning.pitch(72.0), 69.0 + 12.0 * (2.0f32 / (5.0 / 3.0)).log2());
+    }
+}